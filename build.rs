@@ -1,6 +0,0 @@
-extern crate gcc;
-
-fn main() {
-    // compile libsph
-    gcc::Build::new().file("ext/shabal.c").compile("libsph_shabal.a");
-}
\ No newline at end of file
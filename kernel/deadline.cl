@@ -0,0 +1,22 @@
+// Deadlines of the scoop of a block for every nonce computed by nonce_step2..4.
+// The deadline is the first 8 bytes of shabal256(generation signature || scoop), little endian,
+// divided by the base target.
+__kernel void calculate_deadlines(__global unsigned char* p_buffer, unsigned int p_size, __global unsigned char* p_generationSignature,
+                                  unsigned int p_scoop, unsigned long p_baseTarget, __global unsigned long* p_deadlines) {
+	size_t id = get_global_id(0);
+	if(id >= p_size) {
+		return;
+	}
+
+	unsigned char data[HASH_SIZE + SCOOP_SIZE];
+	memcpyFromGlobal(p_generationSignature, 0, data, 0, HASH_SIZE);
+	memcpyFromGlobal(p_buffer, GEN_SIZE * id + p_scoop * SCOOP_SIZE, data, HASH_SIZE, SCOOP_SIZE);
+
+	unsigned char hash[HASH_SIZE];
+	shabal_context_t context;
+	shabal_init(&context);
+	shabal_update(&context, data, 0, HASH_SIZE + SCOOP_SIZE);
+	shabal_digest(&context, hash, 0);
+
+	p_deadlines[id] = decodeLongLE(hash, 0) / p_baseTarget;
+}
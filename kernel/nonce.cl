@@ -84,4 +84,25 @@ __kernel void nonce_step3(__global unsigned char* p_buffer, unsigned int p_size)
 	for(unsigned int i = 0 ; i < len ; ++i) {
 		p_buffer[offset + i] ^= hash[i % HASH_SIZE];
 	}
-}
\ No newline at end of file
+}
+
+// PoC2: swap the second hash of every scoop with the second hash of the mirrored scoop
+__kernel void nonce_step4_poc2(__global unsigned char* p_buffer, unsigned int p_size) {
+	size_t id = get_global_id(0);
+	if(id >= p_size) {
+		return;
+	}
+
+	unsigned int offset = GEN_SIZE * id;
+
+	for(unsigned int scoop = 0 ; scoop < SCOOPS_PER_PLOT / 2 ; ++scoop) {
+		unsigned int low = offset + scoop * SCOOP_SIZE + HASH_SIZE;
+		unsigned int high = offset + (SCOOPS_PER_PLOT - 1 - scoop) * SCOOP_SIZE + HASH_SIZE;
+
+		for(unsigned int i = 0 ; i < HASH_SIZE ; ++i) {
+			unsigned char tmp = p_buffer[low + i];
+			p_buffer[low + i] = p_buffer[high + i];
+			p_buffer[high + i] = tmp;
+		}
+	}
+}
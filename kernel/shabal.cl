@@ -309,6 +309,28 @@ void shabal_update(shabal_context_t* p_context, unsigned char* p_data, unsigned
 		SHABAL_XOR_W;
 		SHABAL_APPLY_P;
 
+		for(unsigned int i = 0 ; i < 3 ; ++i) {
+			SHABAL_SWAP_BC;
+			SHABAL_XOR_W;
+			SHABAL_APPLY_P;
+		}
+	} else if(numRemaining == 32) {
+		unsigned long base = (p_offset >> 2) + (numFullRounds << 4);
+		M0 = dataView[base];
+		M1 = dataView[base + 1];
+		M2 = dataView[base + 2];
+		M3 = dataView[base + 3];
+		M4 = dataView[base + 4];
+		M5 = dataView[base + 5];
+		M6 = dataView[base + 6];
+		M7 = dataView[base + 7];
+		M8 = 0x80;
+		M9 = MA = MB = MC = MD = ME = MF = 0;
+
+		SHABAL_INPUT_BLOCK_ADD;
+		SHABAL_XOR_W;
+		SHABAL_APPLY_P;
+
 		for(unsigned int i = 0 ; i < 3 ; ++i) {
 			SHABAL_SWAP_BC;
 			SHABAL_XOR_W;
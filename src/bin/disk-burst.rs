@@ -0,0 +1,120 @@
+extern crate burst_rust;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use burst_rust::util::config::CONFIG;
+use burst_rust::util::config::{DiskConfig, WorkConfig};
+use burst_rust::mine::{CancellationToken, MiningInfo, MiningInfoListener, NonceSubmitter, format_duration_from_seconds};
+use burst_rust::mine::scanner::{PlotScanner, ScanEvent};
+use burst_rust::plot::file::PlotFile;
+use burst_rust::plot::inventory::PlotInventory;
+use burst_rust::util::constants::PLOT_SIZE;
+
+fn main() {
+    let disk_config: DiskConfig = CONFIG.get("disk").expect("Missing disk config");
+    let work_config: WorkConfig = CONFIG.get("work").expect("Missing work config");
+
+    let plot_files = open_plot_files(&disk_config);
+    let inventory = PlotInventory::from_plot_files(&plot_files);
+    for overlap in inventory.overlaps() {
+        eprintln!("Warning: {} and {} both contain the {} nonces starting at {}",
+                  overlap.first.display(), overlap.second.display(), overlap.nonces, overlap.start_nonce);
+    }
+    eprintln!("Target Deadline: {}", format_duration_from_seconds(work_config.target_deadline()));
+
+    let mut nonce_submitters = HashMap::new();
+    for plot_file in &plot_files {
+        let account_id = plot_file.info().account_id();
+        nonce_submitters.entry(account_id)
+            .or_insert_with(|| NonceSubmitter::for_account(work_config.clone(), account_id));
+    }
+
+    let scanner = PlotScanner::new(plot_files, disk_config.read_buffer_nonces(), disk_config.hashing_threads());
+    eprintln!("Mining {} plot files with {}GB ({}GB unique) on {} devices", scanner.plot_files(),
+              inventory.total_nonces() * PLOT_SIZE as u64 / (1024 * 1024 * 1024),
+              inventory.unique_nonces() * PLOT_SIZE as u64 / (1024 * 1024 * 1024), scanner.devices());
+
+    let (mining_info_tx, mining_info_rx) = channel();
+    MiningInfoListener::start(work_config.clone(), mining_info_tx);
+
+    let mut next_block = Some(mining_info_rx.recv().expect("Could not get mining info"));
+    while let Some((mining_info, cancellation)) = next_block.take() {
+        for nonce_submitter in nonce_submitters.values() {
+            nonce_submitter.cancel_submission();
+        }
+        next_block = Some(mine_block(mining_info, cancellation, &scanner, &nonce_submitters, &work_config, &mining_info_rx));
+    }
+}
+
+/// Collect the deadlines of one block. Returns the next block, which cancels the scan of this one.
+fn mine_block(mining_info: MiningInfo, cancellation: CancellationToken, scanner: &PlotScanner,
+              nonce_submitters: &HashMap<u64, NonceSubmitter>, work_config: &WorkConfig,
+              mining_info_rx: &Receiver<(MiningInfo, CancellationToken)>) -> (MiningInfo, CancellationToken) {
+    eprintln!("================\nNew Block\nHeight: {:}\nScoop: {:}\n================",
+              mining_info.height(), mining_info.scoop_number());
+
+    let start = Instant::now();
+    let events = scanner.scan(&mining_info, cancellation.clone());
+    let mut best_deadlines: HashMap<u64, u64> = HashMap::new();
+    loop {
+        if let Ok(next_block) = mining_info_rx.try_recv() {
+            return next_block;
+        }
+
+        match events.recv_timeout(Duration::from_millis(100)) {
+            Ok(ScanEvent::Deadline { account_id, nonce, deadline }) => {
+                let best_deadline = best_deadlines.entry(account_id).or_insert(<u64>::max_value());
+                if deadline < *best_deadline {
+                    *best_deadline = deadline;
+                    eprintln!("Account: {} Nonce: {}: DL {:?} = {} ", account_id, nonce, deadline, format_duration_from_seconds(deadline));
+
+                    if deadline <= work_config.target_deadline() && !cancellation.is_cancelled() {
+                        nonce_submitters[&account_id].submit(nonce, &cancellation);
+                    }
+                }
+            },
+            Ok(ScanEvent::PlotFileScanned { path, nonces, duration }) => {
+                let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+                eprintln!("Scanned {} ({} nonces) in {:.2}s", path.display(), nonces, seconds);
+            },
+            Ok(ScanEvent::PlotFileSkipped { path, reason }) => eprintln!("Skipped {}: {}", path.display(), reason),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    eprintln!("Scanned all plot files in {}s", start.elapsed().as_secs());
+
+    mining_info_rx.recv().expect("Could not get mining info")
+}
+
+fn open_plot_files(disk_config: &DiskConfig) -> Vec<PlotFile> {
+    let mut plot_files = Vec::new();
+    for directory in disk_config.directories() {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Could not read plot directory {}: {}", directory, e);
+                continue;
+            },
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            match PlotFile::open(entry.path()) {
+                Ok(mut plot_file) => {
+                    if disk_config.direct_io() {
+                        match plot_file.enable_direct_io() {
+                            Ok(true) => {},
+                            Ok(false) => eprintln!("Direct I/O is not supported for {}, using buffered reads", entry.path().display()),
+                            Err(e) => eprintln!("Direct I/O failed for {}: {}", entry.path().display(), e),
+                        }
+                    }
+                    plot_files.push(plot_file)
+                },
+                Err(e) => eprintln!("Ignoring {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+    plot_files
+}
@@ -4,24 +4,59 @@
 extern crate burst_rust;
 
 use std::sync::mpsc::channel;
+use std::fmt::Display;
 use std::time::Instant;
 
-use burst_rust::util::config::CONFIG;
-use burst_rust::util::config::{DeviceConfig, WorkConfig};
+use burst_rust::util::config::{CONFIG, device_configs};
+use burst_rust::util::config::{Backend, CpuConfig, WorkConfig};
 use burst_rust::plot::ocl_nonce_computer::OclNonceComputer;
+use burst_rust::plot::cpu_nonce_computer::CpuNonceComputer;
 use burst_rust::mine::{MiningInfo, format_duration_from_seconds};
 use burst_rust::util::constants::PLOT_SIZE;
 use burst_rust::mine::MiningInfoListener;
 use burst_rust::mine::NonceSubmitter;
-use burst_rust::plot::ocl_nonce_computer::continuous_nonce_computer;
+use burst_rust::plot::{NonceComputer, parallel_deadline_computer, nonces_per_minute};
 use std::sync::mpsc::sync_channel;
-use burst_rust::plot::PlotResult;
 
 fn main() {
-    let device_config: DeviceConfig = CONFIG.get("device").expect("Missing device config");
+    let device_configs = device_configs(&CONFIG).expect("Missing device config");
     let work_config: WorkConfig = CONFIG.get("work").expect("Missing work config");
 
+    let mut nonce_computers: Vec<Box<NonceComputer + Send>> = Vec::new();
+    for device_config in device_configs {
+        match device_config.backend() {
+            Backend::OpenCl if device_config.all_devices() => {
+                let all_devices = OclNonceComputer::all_devices(device_config.clone(), work_config.address(), work_config.plot_format())
+                    .unwrap_or_else(|e| exit_with_opencl_error(e));
+                for nonce_computer in all_devices {
+                    nonce_computers.push(Box::new(nonce_computer));
+                }
+            },
+            Backend::OpenCl => {
+                let nonce_computer = OclNonceComputer::new(device_config.clone(), work_config.address(), work_config.plot_format())
+                    .unwrap_or_else(|e| exit_with_opencl_error(e));
+                nonce_computers.push(Box::new(nonce_computer));
+            },
+            Backend::Cpu => {
+                let cpu_config: CpuConfig = CONFIG.get("cpu").expect("Missing cpu config");
+                nonce_computers.push(Box::new(CpuNonceComputer::new(work_config.address(), work_config.plot_format(), cpu_config.nonces_per_batch())));
+            },
+        }
+    }
+    if nonce_computers.is_empty() {
+        eprintln!("No device found");
+        std::process::exit(1);
+    }
+
+    mine(nonce_computers, work_config)
+}
+
+fn mine<C: NonceComputer + Send + 'static>(nonce_computers: Vec<C>, work_config: WorkConfig) {
     eprintln!("Target Deadline: {}", format_duration_from_seconds(work_config.target_deadline()));
+    for (index, nonce_computer) in nonce_computers.iter().enumerate() {
+        eprintln!("Device {}: computing {} nonces per batch on {}", index, nonce_computer.batch_size(), nonce_computer.device_name());
+    }
+    let devices = nonce_computers.len();
 
     let (mining_info_tx, mining_info_rx) = channel();
 
@@ -29,63 +64,80 @@ fn main() {
 
     let nonce_submitter = NonceSubmitter::new(work_config.clone());
 
-    let nonce_computer = OclNonceComputer::new(device_config.clone(), work_config.address()).expect("Invalid config");
+    let (mut mining_info, mut cancellation) = mining_info_rx.recv().expect("Could not get mining info");
+    print_mining_info(&mining_info);
+    check_plot_format(&mining_info, &work_config);
 
-    let (nonces_tx, nonces_rx) = sync_channel(2);
-    let (nonces_idx_tx, nonces_idx_rx) = channel();
-    continuous_nonce_computer(nonce_computer, nonces_idx_rx, nonces_tx);
-
-    let mut mining_info = mining_info_rx.recv().expect("Could not get mining info");
-    let mut scoop_number = mining_info.scoop_number();
-    print_mining_info(&mining_info, scoop_number);
+    let (deadlines_tx, deadlines_rx) = sync_channel(2 * devices);
+    let (blocks_tx, blocks_rx) = channel();
+    blocks_tx.send((mining_info.clone(), cancellation.clone())).expect("could not start computing deadlines");
+    parallel_deadline_computer(nonce_computers, blocks_rx, deadlines_tx);
 
     let mut start = Instant::now();
+    let mut batch_starts = vec![start; devices];
+    let mut device_nonces = vec![0u64; devices];
     let mut best_deadline = <u64>::max_value();
     loop {
-        if let Ok(new_mining_info) = mining_info_rx.try_recv() {
+        if let Ok((new_mining_info, new_cancellation)) = mining_info_rx.try_recv() {
             mining_info = new_mining_info;
-            scoop_number = mining_info.scoop_number();
+            cancellation = new_cancellation;
             best_deadline = <u64>::max_value();
-            nonces_idx_tx.send(0).expect("could not reset nonce");
+            blocks_tx.send((mining_info.clone(), cancellation.clone())).expect("could not restart computing deadlines");
             nonce_submitter.cancel_submission();
             start = Instant::now();
-            print_mining_info(&mining_info, scoop_number);
+            batch_starts = vec![start; devices];
+            device_nonces = vec![0u64; devices];
+            print_mining_info(&mining_info);
+            check_plot_format(&mining_info, &work_config);
         }
 
-        let plot = nonces_rx.recv().expect("could not get next nonces");
-
-        let deadlines = compute_deadlines(&mining_info, &plot, scoop_number);
+        let (device, deadlines) = deadlines_rx.recv().expect("could not get next deadlines");
+        if cancellation.is_cancelled() || deadlines.height() != mining_info.height() {
+            // the batch was computed for a previous block
+            continue;
+        }
 
-        let &(nonce, new_best_deadline) = deadlines.iter().min_by_key(|a| a.1).expect("No best deadline found");
+        let (nonce, new_best_deadline) = deadlines.best().expect("No best deadline found");
 
         if new_best_deadline < best_deadline {
             best_deadline = new_best_deadline;
             eprintln!("Nonce: {}: DL {:?} = {} ", nonce, best_deadline, format_duration_from_seconds(best_deadline));
 
-            if best_deadline <= work_config.target_deadline() {
-                nonce_submitter.submit(nonce);
+            // the next block may have arrived while the batch was searched
+            if best_deadline <= work_config.target_deadline() && !cancellation.is_cancelled() {
+                nonce_submitter.submit(nonce, &cancellation);
             }
         }
 
-        let current_nonce = plot.start_nonce() + plot.num_nonces();
-        eprintln!("Current Block Nonce: {} => Pseudo Plot Size: {}GB. Speed: {:.0} Nonces/min",
-                  current_nonce,
-                  current_nonce * PLOT_SIZE as u64 / (1024 * 1024 * 1024),
-                  current_nonce as f64 / (start.elapsed().as_secs() as f64 / 60.0));
+        device_nonces[device] += deadlines.num_nonces();
+        let block_nonces: u64 = device_nonces.iter().sum();
+        eprintln!("Block Nonces: {} => Pseudo Plot Size: {}GB. Speed: {:.0} Nonces/min, device {}: {:.0} Nonces/min, last batch {:.0} Nonces/min",
+                  block_nonces,
+                  block_nonces * PLOT_SIZE as u64 / (1024 * 1024 * 1024),
+                  nonces_per_minute(block_nonces, start.elapsed()),
+                  device,
+                  nonces_per_minute(device_nonces[device], start.elapsed()),
+                  nonces_per_minute(deadlines.num_nonces(), batch_starts[device].elapsed()));
+        batch_starts[device] = Instant::now();
     }
 }
 
-fn compute_deadlines(mining_info: &MiningInfo, plot: &PlotResult, scoop_number: u16) -> Vec<(u64, u64)> {
-    (plot.start_nonce()..plot.start_nonce() + plot.num_nonces())
-        .zip(plot.nonces()
-            .map(|nonce|
-                nonce.scoop_data(scoop_number).calculate_deadline(&mining_info)
-                    .expect("Deadline could not be calculated"))).collect()
+fn exit_with_opencl_error<E: Display>(error: E) -> ! {
+    eprintln!("Could not set up the OpenCL device: {}", error);
+    eprintln!("Run `plot-burst devices` to list the available devices");
+    std::process::exit(1);
 }
 
-fn print_mining_info(mining_info: &MiningInfo, scoop_number: u16) {
+fn print_mining_info(mining_info: &MiningInfo) {
     eprintln!("================\nNew Block\nHeight: {:}\nScoop: {:}\n================",
-              mining_info.height(), scoop_number);
+              mining_info.height(), mining_info.scoop_number());
+}
+
+fn check_plot_format(mining_info: &MiningInfo, work_config: &WorkConfig) {
+    if mining_info.plot_format() != work_config.plot_format() {
+        eprintln!("Warning: computing {:?} nonces, but height {} requires {:?}. Deadlines will be rejected.",
+                  work_config.plot_format(), mining_info.height(), mining_info.plot_format());
+    }
 }
 
 #[cfg(test)]
@@ -103,12 +155,8 @@ mod test {
         };
 
 
-        let mining_info = MiningInfo {
-            generation_signature: vec![233, 36, 246, 242, 87, 223, 13, 96, 189, 243, 238, 93, 70, 224, 34, 49, 217, 12, 178, 207, 182, 244, 24, 126, 226, 177, 148, 68, 138, 37, 253, 176],
-            height: 465699,
-            base_target: 43899,
-            scoop_number: None,
-        };
+        let generation_signature = vec![233, 36, 246, 242, 87, 223, 13, 96, 189, 243, 238, 93, 70, 224, 34, 49, 217, 12, 178, 207, 182, 244, 24, 126, 226, 177, 148, 68, 138, 37, 253, 176];
+        let mining_info = MiningInfo::new(generation_signature, 465699, 43899, None);
 
         let work_config = WorkConfig {
             address: 11433454602339013530,
@@ -0,0 +1,292 @@
+extern crate burst_rust;
+#[macro_use] extern crate clap;
+
+use std::cmp::max;
+use std::sync::mpsc::{channel, sync_channel};
+use std::time::Instant;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use burst_rust::util::config::{CONFIG, device_configs};
+use burst_rust::util::config::{Backend, CpuConfig, DiskConfig, WorkConfig};
+use burst_rust::util::constants::PLOT_SIZE;
+use burst_rust::plot::ocl_nonce_computer::OclNonceComputer;
+use burst_rust::plot::ocl_device;
+use burst_rust::plot::cpu_nonce_computer::CpuNonceComputer;
+use burst_rust::plot::{NonceComputer, PlotFormat, continuous_nonce_computer, nonces_per_minute};
+use burst_rust::plot::writer::{PlotWriter, incomplete_plot_files};
+use burst_rust::plot::{converter, optimizer};
+use burst_rust::plot::file::PlotFile;
+use burst_rust::plot::inventory::PlotInventory;
+use burst_rust::plot::verify::{self, ScoopSelection};
+
+fn main() {
+    let matches = App::new("plot-burst")
+        .about("Creates and maintains burst plot files")
+        .subcommand(SubCommand::with_name("plot")
+            .about("Generates a plot file")
+            .arg(Arg::with_name("directory").long("dir").takes_value(true).required(true)
+                .help("Directory the plot file is created in"))
+            .arg(Arg::with_name("start_nonce").long("start-nonce").takes_value(true)
+                .help("Defaults to the first nonce not plotted yet for the account in this and the configured disk directories"))
+            .arg(Arg::with_name("nonces").long("nonces").takes_value(true).required_unless("resume"))
+            .arg(Arg::with_name("resume").long("resume")
+                .help("Continue the incomplete plot files in the directory instead of starting a new one"))
+            .arg(Arg::with_name("account").long("account").takes_value(true)
+                .help("Numeric account id, defaults to the address of the work config"))
+            .arg(Arg::with_name("stagger").long("stagger").takes_value(true)
+                .help("Write a legacy staggered PoC1 file"))
+            .arg(Arg::with_name("format").long("format").takes_value(true).possible_values(&["poc1", "poc2"])
+                .help("Plot format, defaults to the plot format of the work config"))
+            .arg(Arg::with_name("memory").long("memory").takes_value(true).default_value("1024")
+                .help("Memory in MiB for collecting nonces before they are written, 256KiB per nonce"))
+            .arg(Arg::with_name("direct_io").long("direct-io")
+                .help("Write with O_DIRECT, bypassing the page cache")))
+        .subcommand(SubCommand::with_name("optimize")
+            .about("Rewrites a staggered plot file into the optimized layout")
+            .arg(Arg::with_name("file").required(true)
+                .help("Staggered plot file"))
+            .arg(Arg::with_name("out_dir").long("out-dir").takes_value(true)
+                .help("Write an optimized copy to this directory instead of rewriting the file in place"))
+            .arg(Arg::with_name("memory").long("memory").takes_value(true).default_value("1024")
+                .help("Memory used for buffering in MiB")))
+        .subcommand(SubCommand::with_name("convert")
+            .about("Converts an optimized PoC1 plot file to PoC2 in place")
+            .arg(Arg::with_name("file").required(true)
+                .help("Optimized PoC1 plot file"))
+            .arg(Arg::with_name("memory").long("memory").takes_value(true).default_value("1024")
+                .help("Memory used for buffering in MiB")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Regenerates random nonces of a plot file and compares them with the file")
+            .arg(Arg::with_name("file").required(true))
+            .arg(Arg::with_name("samples").long("samples").takes_value(true).default_value("64")
+                .help("Nonces to regenerate, the first and last nonce are always checked"))
+            .arg(Arg::with_name("scoops").long("scoops").takes_value(true).default_value("all")
+                .help("Number of random scoops compared per nonce or \"all\"")))
+        .subcommand(SubCommand::with_name("devices")
+            .about("Lists all OpenCL devices and the device config values they accept"))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("plot", Some(args)) => plot(args),
+        ("optimize", Some(args)) => optimize(args),
+        ("convert", Some(args)) => convert(args),
+        ("verify", Some(args)) => verify(args),
+        ("devices", Some(_)) => devices(),
+        _ => {
+            eprintln!("{}", matches.usage());
+            std::process::exit(1);
+        },
+    }
+}
+
+fn plot(args: &ArgMatches) {
+    let work_config: WorkConfig = CONFIG.get("work").expect("Missing work config");
+    let memory_bytes = value_t_or_exit!(args, "memory", u64) * 1024 * 1024;
+    let buffer_nonces = max(1, memory_bytes / PLOT_SIZE as u64);
+
+    if args.is_present("resume") {
+        let directory = args.value_of("directory").unwrap();
+        let paths = incomplete_plot_files(directory).expect("Could not read plot directory");
+        if paths.is_empty() {
+            eprintln!("No incomplete plot files in {}", directory);
+        }
+        for path in paths {
+            let mut writer = PlotWriter::resume(&path, buffer_nonces).expect("Could not resume plot file");
+            eprintln!("Resuming {} at nonce {}", path.display(), writer.next_nonce());
+            if args.is_present("direct_io") {
+                enable_direct_io(&mut writer);
+            }
+            plot_with_configured_backend(writer);
+        }
+        return;
+    }
+
+    let account_id = if args.is_present("account") {
+        value_t_or_exit!(args, "account", u64)
+    } else {
+        work_config.address()
+    };
+    let format = match args.value_of("format") {
+        Some("poc1") => PlotFormat::PoC1,
+        Some(_) => PlotFormat::PoC2,
+        None => work_config.plot_format(),
+    };
+    let stagger = if args.is_present("stagger") { Some(value_t_or_exit!(args, "stagger", u64)) } else { None };
+    let directory = args.value_of("directory").unwrap();
+    let nonces = value_t_or_exit!(args, "nonces", u64);
+    let start_nonce = if args.is_present("start_nonce") {
+        value_t_or_exit!(args, "start_nonce", u64)
+    } else {
+        let start_nonce = next_free_start_nonce(directory, account_id, nonces);
+        eprintln!("Using start nonce {}", start_nonce);
+        start_nonce
+    };
+
+    let mut writer = PlotWriter::create(directory,
+                                        account_id,
+                                        start_nonce,
+                                        nonces,
+                                        stagger,
+                                        format,
+                                        buffer_nonces)
+        .expect("Could not create plot file");
+    if args.is_present("direct_io") {
+        enable_direct_io(&mut writer);
+    }
+
+    plot_with_configured_backend(writer);
+}
+
+fn enable_direct_io(writer: &mut PlotWriter) {
+    if !writer.enable_direct_io().expect("Could not enable direct I/O") {
+        eprintln!("Direct I/O is not supported for {}, using buffered writes", writer.path().display());
+    }
+}
+
+/// First nonce range of the account that is free in the plot directory and all mined directories
+fn next_free_start_nonce(directory: &str, account_id: u64, nonces: u64) -> u64 {
+    let mut directories = vec![directory.to_string()];
+    if let Ok(disk_config) = CONFIG.get::<DiskConfig>("disk") {
+        directories.extend(disk_config.directories().iter().cloned());
+    }
+    let inventory = PlotInventory::scan_directories(&directories).expect("Could not read plot directories");
+    inventory.next_free_start_nonce(account_id, nonces)
+}
+
+/// Plot on the first configured device
+fn plot_with_configured_backend(writer: PlotWriter) {
+    let device_config = device_configs(&CONFIG).expect("Missing device config").into_iter().next().expect("No device configured");
+    let account_id = writer.account_id();
+    let format = writer.format();
+
+    match device_config.backend() {
+        Backend::OpenCl => {
+            let nonce_computer = OclNonceComputer::new(device_config.clone(), account_id, format).unwrap_or_else(|e| {
+                eprintln!("Could not set up the OpenCL device: {}", e);
+                std::process::exit(1);
+            });
+            write_plot(nonce_computer, writer)
+        },
+        Backend::Cpu => {
+            let cpu_config: CpuConfig = CONFIG.get("cpu").expect("Missing cpu config");
+            write_plot(CpuNonceComputer::new(account_id, format, cpu_config.nonces_per_batch()), writer)
+        },
+    }
+}
+
+fn optimize(args: &ArgMatches) {
+    let file = args.value_of("file").unwrap();
+    let memory_bytes = value_t_or_exit!(args, "memory", usize) * 1024 * 1024;
+
+    eprintln!("Optimizing {}", file);
+    let result = match args.value_of("out_dir") {
+        Some(directory) => optimizer::optimize_to(file, directory, memory_bytes),
+        None => optimizer::optimize_in_place(file, memory_bytes),
+    };
+    match result {
+        Ok(path) => eprintln!("Finished {}", path.display()),
+        Err(e) => {
+            eprintln!("Optimizing {} failed: {}", file, e);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn convert(args: &ArgMatches) {
+    let file = args.value_of("file").unwrap();
+    let memory_bytes = value_t_or_exit!(args, "memory", usize) * 1024 * 1024;
+
+    eprintln!("Converting {} to PoC2", file);
+    match converter::convert_to_poc2(file, memory_bytes) {
+        Ok(path) => eprintln!("Finished {}", path.display()),
+        Err(e) => {
+            eprintln!("Converting {} failed: {}", file, e);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn verify(args: &ArgMatches) {
+    let file = args.value_of("file").unwrap();
+    let samples = value_t_or_exit!(args, "samples", u64);
+    let scoops = match args.value_of("scoops") {
+        Some("all") | None => ScoopSelection::All,
+        Some(_) => ScoopSelection::Random(value_t_or_exit!(args, "scoops", usize)),
+    };
+    if scoops == ScoopSelection::Random(0) {
+        eprintln!("--scoops has to be at least 1 or \"all\"");
+        std::process::exit(1);
+    }
+
+    let report = PlotFile::open(file)
+        .and_then(|mut plot_file| verify::verify_plot_file(&mut plot_file, samples, scoops))
+        .unwrap_or_else(|e| {
+            eprintln!("Verifying {} failed: {}", file, e);
+            std::process::exit(1);
+        });
+
+    eprintln!("Checked {} scoops of {} nonces", report.checked_scoops, report.checked_nonces);
+    for range in &report.corrupted {
+        println!("Corrupted nonces {} to {}, possibly {} to {}",
+                 range.first_nonce, range.last_nonce, range.possible_first_nonce, range.possible_last_nonce);
+    }
+    if !report.is_intact() {
+        std::process::exit(1);
+    }
+    eprintln!("{} is intact", file);
+}
+
+fn devices() {
+    let devices = ocl_device::list_devices().unwrap_or_else(|e| {
+        eprintln!("Listing OpenCL devices failed: {}", e);
+        std::process::exit(1);
+    });
+    if devices.is_empty() {
+        eprintln!("No OpenCL device found, set backend = \"cpu\" to compute nonces on the CPU");
+        std::process::exit(1);
+    }
+
+    let buffers = device_configs(&CONFIG).ok()
+        .and_then(|device_configs| device_configs.first().map(|device_config| device_config.buffers()))
+        .unwrap_or(2);
+    for device in devices {
+        println!("Platform {}: {}", device.platform_id, device.platform_name);
+        println!("  Device {}: {} ({}), {}, driver {}", device.device_id, device.name, device.vendor, device.version, device.driver_version);
+        println!("    Compute units: {}, global memory: {}MiB, local memory: {}KiB, max allocation: {}MiB, max work group size: {}",
+                 device.compute_units, device.global_memory / (1024 * 1024), device.local_memory / 1024,
+                 device.max_allocation / (1024 * 1024), device.max_work_group_size);
+        println!("    Config: platform_id = {}, device_id = {}, local_work_size up to {}, \
+                  global_work_size a multiple of local_work_size up to {} with buffers = {}",
+                 device.platform_id, device.device_id, device.max_work_group_size,
+                 device.max_global_work_size(buffers), buffers);
+    }
+}
+
+fn write_plot<C: NonceComputer + Send + 'static>(mut nonce_computer: C, mut writer: PlotWriter) {
+    eprintln!("Plotting {} on {}", writer.path().display(), nonce_computer.device_name());
+
+    nonce_computer.set_nonce(writer.next_nonce());
+    let (nonces_tx, nonces_rx) = sync_channel(1);
+    let (_nonces_idx_tx, nonces_idx_rx) = channel();
+    continuous_nonce_computer(nonce_computer, nonces_idx_rx, nonces_tx);
+
+    let start = Instant::now();
+    let mut batch_start = start;
+    let total_nonces = writer.remaining_nonces();
+    while writer.remaining_nonces() > 0 {
+        let plot = nonces_rx.recv().expect("could not get next nonces");
+        let remaining_nonces = writer.remaining_nonces();
+        writer.write(&plot).expect("writing nonces failed");
+
+        let written_nonces = total_nonces - writer.remaining_nonces();
+        eprintln!("Written {}/{} nonces. Speed: {:.0} Nonces/min, last batch {:.0} Nonces/min",
+                  written_nonces, total_nonces,
+                  nonces_per_minute(written_nonces, start.elapsed()),
+                  nonces_per_minute(remaining_nonces - writer.remaining_nonces(), batch_start.elapsed()));
+        batch_start = Instant::now();
+    }
+
+    let path = writer.finish().expect("Could not finish plot file");
+    eprintln!("Finished {}", path.display());
+}
@@ -1,5 +1,4 @@
 #![feature(iterator_step_by)]
-#![feature(libc)]
 #![feature(conservative_impl_trait)]
 
 extern crate ocl;
@@ -15,6 +14,9 @@ extern crate reqwest;
 extern crate config;
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate arrayref;
+extern crate rayon;
+extern crate libc;
+extern crate rand;
 
 pub mod mine;
 pub mod plot;
@@ -1,21 +1,36 @@
 use byteorder::{ByteOrder, BigEndian};
-use util::sph_shabal;
+use util::sph_shabal::Shabal256;
 use reqwest;
+use serde::{Deserialize, Deserializer};
 use serde_json;
 use util::deserialization::{from_str, bytes_from_hex_string};
 use failure::Error;
-use std::io::{Read, Write};
-use util::constants::SCOOPS_PER_PLOT;
+use std::io::Read;
+use util::constants::{SCOOPS_PER_PLOT, POC2_START_HEIGHT};
+use plot::PlotFormat;
 use std::sync::mpsc::Sender;
 use util::config::WorkConfig;
 use std::thread;
 use std;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-#[serde(rename_all = "camelCase")]
+pub mod scanner;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct MiningInfo {
+    generation_signature: Vec<u8>,
+    height: u64,
+    base_target: u64,
+    scoop_number: u16,
+}
+
+/// Mining info as sent by the wallet, which may leave out the scoop number
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WalletMiningInfo {
     #[serde(deserialize_with = "bytes_from_hex_string")]
     generation_signature: Vec<u8>,
     #[serde(deserialize_with = "from_str")]
@@ -25,7 +40,19 @@ pub struct MiningInfo {
     scoop_number: Option<u16>,
 }
 
+impl<'de> Deserialize<'de> for MiningInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let info = WalletMiningInfo::deserialize(deserializer)?;
+        Ok(Self::new(info.generation_signature, info.height, info.base_target, info.scoop_number))
+    }
+}
+
 impl MiningInfo {
+    /// Block information as sent by the wallet. A `scoop_number` of `None` derives it from the generation signature and height.
+    pub fn new(generation_signature: Vec<u8>, height: u64, base_target: u64, scoop_number: Option<u16>) -> Self {
+        let scoop_number = scoop_number.unwrap_or_else(|| calculate_scoop_number(&generation_signature, height));
+        Self { generation_signature, height, base_target, scoop_number }
+    }
     pub fn generation_signature(&self) -> &[u8] {
         &self.generation_signature[..]
     }
@@ -35,29 +62,38 @@ impl MiningInfo {
     pub fn base_target(&self) -> u64 {
         self.base_target
     }
-    fn calculate_scoop_number(&mut self) -> Result<u16, Error> {
-        let mut height = [0u8; 8];
-        BigEndian::write_u64(&mut height, self.height);
-
-        let mut scoop_prefix: [u8; 40] = [0; 40];
-
-        (&mut scoop_prefix[0..32]).write(&self.generation_signature[..])?;
-        (&mut scoop_prefix[32..40]).write(&height)?;
-
-        let scoop_prefix_shabal = sph_shabal::shabal256(&scoop_prefix);
-
-        Ok(BigEndian::read_u16(&scoop_prefix_shabal[30..]) % SCOOPS_PER_PLOT as u16)
-    }
-    pub fn scoop_number(&mut self) -> u16 {
-        if let Some(scoop_number) = self.scoop_number {
-            scoop_number
+    /// Plot format the chain expects at this height
+    pub fn plot_format(&self) -> PlotFormat {
+        if self.height >= POC2_START_HEIGHT {
+            PlotFormat::PoC2
         } else {
-            let scoop_number = self.calculate_scoop_number().expect("could not calculate scoop number");
-            self.scoop_number = Some(scoop_number);
-            scoop_number
+            PlotFormat::PoC1
         }
     }
+    /// Hasher that has already absorbed the generation signature.
+    ///
+    /// Clone it once per scoop instead of hashing the signature again for every nonce.
+    pub fn gensig_hasher(&self) -> Shabal256 {
+        let mut hasher = Shabal256::new();
+        hasher.update(&self.generation_signature[..]);
+        hasher
+    }
+    /// Scoop number sent by the wallet, or derived from the generation signature and height
+    pub fn scoop_number(&self) -> u16 {
+        self.scoop_number
+    }
+}
+
+fn calculate_scoop_number(generation_signature: &[u8], height: u64) -> u16 {
+    let mut height_bytes = [0u8; 8];
+    BigEndian::write_u64(&mut height_bytes, height);
+
+    let mut hasher = Shabal256::new();
+    hasher.update(generation_signature);
+    hasher.update(&height_bytes);
+    let scoop_prefix_shabal = hasher.finalize();
 
+    BigEndian::read_u16(&scoop_prefix_shabal[30..]) % SCOOPS_PER_PLOT as u16
 }
 
 pub fn load_mining_info(wallet_url: &str) -> Result<MiningInfo, Error> {
@@ -105,15 +141,35 @@ pub fn format_duration_from_seconds(seconds: u64) -> String {
     String::from(format!("{}y {}m {}d {}h {}m {}s", years, months, days, hours, minutes, seconds))
 }
 
+/// Shared flag telling workers that the block they are working on is outdated
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Polls the wallet and sends every new block together with a token that gets cancelled by the next block
 pub struct MiningInfoListener {
     current_height: u64,
     work_config: WorkConfig,
-    send_channel: Sender<MiningInfo>,
+    cancellation: CancellationToken,
+    send_channel: Sender<(MiningInfo, CancellationToken)>,
 }
 
 impl MiningInfoListener {
-    pub fn start(work_config: WorkConfig, send_channel: Sender<MiningInfo>) {
-        let mut listener = Self {current_height: 0, work_config, send_channel };
+    pub fn start(work_config: WorkConfig, send_channel: Sender<(MiningInfo, CancellationToken)>) {
+        let mut listener = Self {current_height: 0, work_config, cancellation: CancellationToken::new(), send_channel };
         thread::spawn(move || listener.listen());
     }
 
@@ -123,7 +179,9 @@ impl MiningInfoListener {
                 Ok(mining_info) => {
                     if mining_info.height() > self.current_height {
                         self.current_height = mining_info.height();
-                        self.send_channel.send(mining_info).ok();
+                        self.cancellation.cancel();
+                        self.cancellation = CancellationToken::new();
+                        self.send_channel.send((mining_info, self.cancellation.clone())).ok();
                     }
                 },
                 Err(_) => eprintln!("Getting Mining info failed"),
@@ -136,35 +194,51 @@ impl MiningInfoListener {
 
 pub struct NonceSubmitter {
     _work_config: WorkConfig,
-    sender: Sender<Option<u64>>,
+    account_id: u64,
+    sender: Sender<Option<(u64, CancellationToken)>>,
 }
 
 impl NonceSubmitter {
     pub fn new(work_config: WorkConfig) -> Self {
+        let account_id = work_config.address();
+        Self::for_account(work_config, account_id)
+    }
+
+    /// Submitter for nonces plotted for `account_id` instead of the configured address
+    pub fn for_account(work_config: WorkConfig, account_id: u64) -> Self {
         let (tx, rx) = channel();
         let config = work_config.clone();
-        thread::spawn(move || Self::submission_loop(rx, config));
-        Self {_work_config: work_config, sender: tx}
+        thread::spawn(move || Self::submission_loop(rx, config, account_id));
+        Self {_work_config: work_config, account_id, sender: tx}
+    }
+
+    pub fn account_id(&self) -> u64 {
+        self.account_id
     }
 
-    fn submission_loop(nonce_receiver: Receiver<Option<u64>>, work_config: WorkConfig) {
+    fn submission_loop(nonce_receiver: Receiver<Option<(u64, CancellationToken)>>, work_config: WorkConfig, account_id: u64) {
         loop {
             let current_nonce = nonce_receiver.recv().unwrap_or(None);
             let mut retries = 0;
 
-            if let Some(mut nonce) = current_nonce {
+            if let Some((mut nonce, mut cancellation)) = current_nonce {
                 while retries < work_config.submission_retry_number() {
                     if let Ok(new_nonce) = nonce_receiver.try_recv() {
                         match new_nonce {
-                            Some(new_nonce) => {
+                            Some((new_nonce, new_cancellation)) => {
                                 retries = 0;
                                 nonce = new_nonce;
+                                cancellation = new_cancellation;
                             },
                             None => break
                         }
                     }
+                    // the nonce is worthless once the next block arrived
+                    if cancellation.is_cancelled() {
+                        break;
+                    }
 
-                    match submit_deadline(&work_config.wallet_url(), work_config.address(), &work_config.passphrase(), nonce) {
+                    match submit_deadline(&work_config.wallet_url(), account_id, &work_config.passphrase(), nonce) {
                         Ok(result) => {
                             eprintln!("Submitted! {}", result);
                             break;
@@ -181,10 +255,10 @@ impl NonceSubmitter {
 
     }
 
-    pub fn submit(&self, nonce: u64) {
+    /// Submit `nonce` in the background, retrying until it is accepted or `cancellation` is cancelled
+    pub fn submit(&self, nonce: u64, cancellation: &CancellationToken) {
         // TODO: check for target deadline and better earlier submission
-        // TODO: get notified of new mining info and cancel/prevent submission
-        self.sender.send(Some(nonce)).ok();
+        self.sender.send(Some((nonce, cancellation.clone()))).ok();
     }
 
     pub fn cancel_submission(&self) {
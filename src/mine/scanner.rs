@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mine::{CancellationToken, MiningInfo};
+use plot::calculate_deadlines;
+use plot::file::{PlotFile, Scoops};
+
+/// Progress of a scan, sent while plot files are read and hashed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanEvent {
+    /// Best deadline of one read buffer of a plot file
+    Deadline { account_id: u64, nonce: u64, deadline: u64 },
+    /// A plot file was read completely. `duration` includes waiting for the hashing workers.
+    PlotFileScanned { path: PathBuf, nonces: u64, duration: Duration },
+    /// A plot file was not scanned because of an error or the wrong plot format
+    PlotFileSkipped { path: PathBuf, reason: String },
+}
+
+struct ScanRequest {
+    mining_info: MiningInfo,
+    scoop_number: u16,
+    cancellation: CancellationToken,
+    events: Sender<ScanEvent>,
+}
+
+impl ScanRequest {
+    fn send(&self, event: ScanEvent) {
+        if !self.cancellation.is_cancelled() {
+            self.events.send(event).ok();
+        }
+    }
+}
+
+struct Chunk {
+    account_id: u64,
+    scoops: Scoops,
+    request: Arc<ScanRequest>,
+}
+
+/// Scans plot files for the best deadlines of a block.
+///
+/// Plot files are grouped by the device they are stored on and every device gets its own reader
+/// thread, so disks are read in parallel without seeking between files of the same disk. Read
+/// buffers are hashed on a separate pool of workers while the readers continue. Once a scan is
+/// cancelled, readers stop after the current read and queued buffers are dropped unhashed.
+pub struct PlotScanner {
+    readers: Vec<Sender<Arc<ScanRequest>>>,
+    plot_files: usize,
+}
+
+impl PlotScanner {
+    /// `read_buffer_nonces` limits the nonces read at once per disk and is at least 1, `hashing_threads` of 0 uses one worker per core
+    pub fn new(plot_files: Vec<PlotFile>, read_buffer_nonces: u64, hashing_threads: usize) -> Self {
+        let num_plot_files = plot_files.len();
+        let hashing_threads = if hashing_threads == 0 { ::rayon::current_num_threads() } else { hashing_threads };
+        // reading no nonces at a time would never finish a plot file
+        let read_buffer_nonces = ::std::cmp::max(1, read_buffer_nonces);
+
+        // a few buffers per worker keep the workers busy without reading far ahead
+        let (chunks_tx, chunks_rx) = sync_channel(hashing_threads * 2);
+        let chunks_rx = Arc::new(Mutex::new(chunks_rx));
+        for _ in 0..hashing_threads {
+            let chunks_rx = chunks_rx.clone();
+            thread::spawn(move || hashing_worker(chunks_rx));
+        }
+
+        let mut devices: HashMap<u64, Vec<PlotFile>> = HashMap::new();
+        for plot_file in plot_files {
+            devices.entry(device_id(plot_file.path())).or_insert_with(Vec::new).push(plot_file);
+        }
+
+        let readers = devices.into_iter()
+            .map(|(_, plot_files)| {
+                let (requests_tx, requests_rx) = channel();
+                let chunks_tx = chunks_tx.clone();
+                thread::spawn(move || device_reader(plot_files, read_buffer_nonces, requests_rx, chunks_tx));
+                requests_tx
+            })
+            .collect();
+
+        Self { readers, plot_files: num_plot_files }
+    }
+
+    /// Number of devices read in parallel
+    pub fn devices(&self) -> usize {
+        self.readers.len()
+    }
+
+    pub fn plot_files(&self) -> usize {
+        self.plot_files
+    }
+
+    /// Start scanning all plot files for a block. The returned receiver is closed once every plot
+    /// file was scanned or soon after `cancellation` is cancelled. No events are sent after cancellation.
+    pub fn scan(&self, mining_info: &MiningInfo, cancellation: CancellationToken) -> Receiver<ScanEvent> {
+        let mining_info = mining_info.clone();
+        let scoop_number = mining_info.scoop_number();
+        let (events_tx, events_rx) = channel();
+        let request = Arc::new(ScanRequest { mining_info, scoop_number, cancellation, events: events_tx });
+
+        for reader in &self.readers {
+            reader.send(request.clone()).expect("plot reader stopped");
+        }
+        events_rx
+    }
+}
+
+fn device_reader(mut plot_files: Vec<PlotFile>, read_buffer_nonces: u64, requests: Receiver<Arc<ScanRequest>>,
+                 chunks: SyncSender<Chunk>) {
+    for request in requests {
+        for plot_file in plot_files.iter_mut() {
+            if request.cancellation.is_cancelled() {
+                break;
+            }
+            let start = Instant::now();
+            let info = *plot_file.info();
+
+            if info.format() != request.mining_info.plot_format() {
+                let reason = format!("{:?} plot files are not valid at height {}", info.format(), request.mining_info.height());
+                request.send(ScanEvent::PlotFileSkipped { path: plot_file.path().to_path_buf(), reason });
+                continue;
+            }
+
+            let mut nonce_index = 0;
+            while nonce_index < info.nonces() {
+                if request.cancellation.is_cancelled() {
+                    break;
+                }
+                let nonces = ::std::cmp::min(read_buffer_nonces, info.nonces() - nonce_index);
+                match plot_file.read_scoop_range(request.scoop_number, nonce_index, nonces) {
+                    Ok(scoops) => {
+                        let chunk = Chunk { account_id: info.account_id(), scoops, request: request.clone() };
+                        chunks.send(chunk).expect("hashing workers stopped");
+                    },
+                    Err(e) => {
+                        request.send(ScanEvent::PlotFileSkipped { path: plot_file.path().to_path_buf(), reason: e.to_string() });
+                        break;
+                    },
+                }
+                nonce_index += nonces;
+            }
+
+            if nonce_index >= info.nonces() {
+                let event = ScanEvent::PlotFileScanned { path: plot_file.path().to_path_buf(), nonces: info.nonces(), duration: start.elapsed() };
+                request.send(event);
+            }
+        }
+    }
+}
+
+fn hashing_worker(chunks: Arc<Mutex<Receiver<Chunk>>>) {
+    loop {
+        let chunk = match chunks.lock().expect("hashing workers poisoned").recv() {
+            Ok(chunk) => chunk,
+            Err(_) => return,
+        };
+
+        if chunk.request.cancellation.is_cancelled() {
+            continue;
+        }
+
+        let scoops: Vec<_> = chunk.scoops.scoops().collect();
+        let deadlines = calculate_deadlines(&scoops, &chunk.request.mining_info);
+        if let Some((nonce_index, deadline)) = deadlines.into_iter().enumerate().min_by_key(|a| a.1) {
+            let nonce = chunk.scoops.start_nonce() + nonce_index as u64;
+            chunk.request.send(ScanEvent::Deadline { account_id: chunk.account_id, nonce, deadline });
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device_id(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    path.metadata().map(|metadata| metadata.dev()).unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use plot::{PlotFormat, PlotResult};
+    use plot::writer::PlotWriter;
+    use plot::cpu_nonce_computer::generate_nonce;
+    use util::constants::GEN_SIZE;
+
+    #[test]
+    fn finds_best_deadline_of_all_plot_files() {
+        let directory = env::temp_dir().join(format!("burst-rust-scanner-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let generation_signature = vec![233, 36, 246, 242, 87, 223, 13, 96, 189, 243, 238, 93, 70, 224, 34, 49,
+                                        217, 12, 178, 207, 182, 244, 24, 126, 226, 177, 148, 68, 138, 37, 253, 176];
+        let mining_info = MiningInfo::new(generation_signature, 600000, 43899, None);
+
+        let mut plot_files = Vec::new();
+        let mut expected = HashMap::new();
+        for &(account_id, start_nonce) in &[(1u64, 0u64), (1, 5), (2, 0)] {
+            let mut data = vec![0u8; 5 * GEN_SIZE];
+            for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+                generate_nonce(account_id, start_nonce + index as u64, PlotFormat::PoC2, nonce);
+            }
+            let plot = PlotResult::from_bytes(start_nonce, data, PlotFormat::PoC2).unwrap();
+            let best = plot.nonces().enumerate()
+                .map(|(index, nonce)| (nonce.calculate_deadline(&mining_info).unwrap(), start_nonce + index as u64))
+                .min().unwrap();
+            let entry = expected.entry(account_id).or_insert(best);
+            *entry = ::std::cmp::min(*entry, best);
+
+            let mut writer = PlotWriter::create(&directory, account_id, start_nonce, 5, None, PlotFormat::PoC2, 5).unwrap();
+            writer.write(&plot).unwrap();
+            plot_files.push(PlotFile::open(writer.finish().unwrap()).unwrap());
+        }
+
+        let scanner = PlotScanner::new(plot_files, 2, 2);
+        let mut best = HashMap::new();
+        let mut scanned = 0;
+        for event in scanner.scan(&mining_info, CancellationToken::new()) {
+            match event {
+                ScanEvent::Deadline { account_id, nonce, deadline } => {
+                    let entry = best.entry(account_id).or_insert((deadline, nonce));
+                    *entry = ::std::cmp::min(*entry, (deadline, nonce));
+                },
+                ScanEvent::PlotFileScanned { .. } => scanned += 1,
+                ScanEvent::PlotFileSkipped { reason, .. } => panic!("{}", reason),
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(scanned, 3);
+        assert_eq!(best, expected);
+    }
+
+    #[test]
+    fn cancelled_scan_sends_no_events() {
+        let directory = env::temp_dir().join(format!("burst-rust-cancelled-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut writer = PlotWriter::create(&directory, 1, 0, 2, None, PlotFormat::PoC2, 2).unwrap();
+        writer.write(&PlotResult::from_bytes(0, vec![0u8; 2 * GEN_SIZE], PlotFormat::PoC2).unwrap()).unwrap();
+        let plot_file = PlotFile::open(writer.finish().unwrap()).unwrap();
+
+        let mining_info = MiningInfo::new(vec![0u8; 32], 600000, 1, Some(0));
+        let scanner = PlotScanner::new(vec![plot_file], 1, 1);
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let events: Vec<_> = scanner.scan(&mining_info, cancellation).iter().collect();
+
+        // the scanner keeps working for later blocks
+        let next_events: Vec<_> = scanner.scan(&mining_info, CancellationToken::new()).iter().collect();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(events.is_empty());
+        assert_eq!(next_events.len(), 3);
+    }
+}
@@ -0,0 +1,304 @@
+use byteorder::{ByteOrder, LittleEndian};
+use failure::{Error, err_msg};
+use std::cmp::{max, min};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use plot::{PlotFormat, mirrored_scoop};
+use plot::file::{PlotFile, PlotFileInfo};
+use plot::progress::{sidecar_path, write_atomically};
+use plot::verify::spot_check;
+use plot::writer::file_offset;
+use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE, HASH_SIZE};
+
+/// Convert an optimized PoC1 plot file to PoC2 in place.
+///
+/// The second hashes of scoop `n` and scoop `4095 - n` are swapped pair by pair, reading chunks of
+/// at most `memory_bytes` of both scoops at once. The new hashes of every chunk are journaled
+/// before they are written, so an interrupted conversion continues where it stopped when called
+/// again. The file is renamed to the PoC2 naming once done. Returns the path of the converted file.
+pub fn convert_to_poc2<P: AsRef<Path>>(path: P, memory_bytes: usize) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    let info = {
+        let file_name = path.file_name().and_then(|name| name.to_str())
+            .ok_or_else(|| err_msg(format!("{} is not a plot file", path.display())))?;
+        PlotFileInfo::parse(file_name)?
+    };
+    let destination = path.with_file_name(info.with_format(PlotFormat::PoC2).file_name());
+    let journal_path = sidecar_path(&destination, "converting");
+
+    // interrupted after renaming the finished file, called with either of its names
+    if journal_path.exists() && destination.exists() && (info.format() == PlotFormat::PoC2 || !path.exists()) {
+        fs::remove_file(&journal_path)?;
+        return Ok(destination);
+    }
+    if info.format() == PlotFormat::PoC2 {
+        return Err(err_msg(format!("{} is already a PoC2 plot file", path.display())));
+    }
+    if !info.is_optimized() {
+        return Err(err_msg(format!("{} is staggered, PoC2 plot files have to be optimized first", path.display())));
+    }
+
+    // checks the size of the file and that it is complete
+    PlotFile::open(path)?;
+
+    let mut conversion = Conversion {
+        file: OpenOptions::new().read(true).write(true).open(path)?,
+        nonces: info.nonces(),
+        chunk_nonces: max(1, memory_bytes / (2 * SCOOP_SIZE)) as u64,
+        journal_path: journal_path.clone(),
+    };
+    conversion.run()?;
+
+    // the journal stays until the checked file has its final name
+    spot_check(&mut PlotFile::open_as(path, info.with_format(PlotFormat::PoC2))?)?;
+    fs::rename(path, &destination)?;
+    fs::remove_file(&journal_path)?;
+    Ok(destination)
+}
+
+struct Conversion {
+    file: File,
+    nonces: u64,
+    chunk_nonces: u64,
+    journal_path: PathBuf,
+}
+
+impl Conversion {
+    fn run(&mut self) -> Result<(), Error> {
+        let mut scoop_number = 0;
+        let mut nonce_index = 0;
+
+        if let Some(journal) = Journal::load(&self.journal_path)? {
+            self.apply(&journal)?;
+            scoop_number = journal.scoop_number;
+            nonce_index = journal.first_nonce_index + journal.nonces();
+        }
+
+        while scoop_number < SCOOPS_PER_PLOT / 2 {
+            while nonce_index < self.nonces {
+                let nonces = min(self.chunk_nonces, self.nonces - nonce_index);
+                self.convert_chunk(scoop_number, nonce_index, nonces)?;
+                nonce_index += nonces;
+            }
+            scoop_number += 1;
+            nonce_index = 0;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn convert_chunk(&mut self, scoop_number: u16, first_nonce_index: u64, nonces: u64) -> Result<(), Error> {
+        let low = self.read_region(scoop_number, first_nonce_index, nonces)?;
+        let high_scoop = mirrored(scoop_number)?;
+        let high = self.read_region(high_scoop, first_nonce_index, nonces)?;
+
+        let journal = Journal {
+            scoop_number,
+            first_nonce_index,
+            low_hashes: second_hashes(&high),
+            high_hashes: second_hashes(&low),
+        };
+        journal.save(&self.journal_path)?;
+
+        self.write_patched(scoop_number, first_nonce_index, low, &journal.low_hashes)?;
+        self.write_patched(high_scoop, first_nonce_index, high, &journal.high_hashes)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Write the journaled hashes again. The hashes are absolute, so this is safe no matter how much was written before.
+    fn apply(&mut self, journal: &Journal) -> Result<(), Error> {
+        let nonces = journal.nonces();
+        for &(scoop_number, ref hashes) in &[(journal.scoop_number, &journal.low_hashes),
+                                               (mirrored(journal.scoop_number)?, &journal.high_hashes)] {
+            let region = self.read_region(scoop_number, journal.first_nonce_index, nonces)?;
+            self.write_patched(scoop_number, journal.first_nonce_index, region, hashes)?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn read_region(&mut self, scoop_number: u16, first_nonce_index: u64, nonces: u64) -> Result<Vec<u8>, Error> {
+        let mut region = vec![0u8; nonces as usize * SCOOP_SIZE];
+        self.file.seek(SeekFrom::Start(file_offset(first_nonce_index, scoop_number, self.nonces)))?;
+        self.file.read_exact(&mut region)?;
+        Ok(region)
+    }
+
+    /// Replace the second hash of every scoop in `region` and write it back
+    fn write_patched(&mut self, scoop_number: u16, first_nonce_index: u64, mut region: Vec<u8>, hashes: &[u8]) -> Result<(), Error> {
+        let hash_size = HASH_SIZE as usize;
+        for (scoop, hash) in region.chunks_mut(SCOOP_SIZE).zip(hashes.chunks(hash_size)) {
+            scoop[hash_size..].copy_from_slice(hash);
+        }
+        self.file.seek(SeekFrom::Start(file_offset(first_nonce_index, scoop_number, self.nonces)))?;
+        self.file.write_all(&region)?;
+        Ok(())
+    }
+}
+
+/// The scoop trading second hashes with `scoop_number`, an error for scoop numbers out of range
+fn mirrored(scoop_number: u16) -> Result<u16, Error> {
+    mirrored_scoop(scoop_number).ok_or_else(|| err_msg(format!("Scoop number {} is out of range", scoop_number)))
+}
+
+fn second_hashes(region: &[u8]) -> Vec<u8> {
+    let hash_size = HASH_SIZE as usize;
+    region.chunks(SCOOP_SIZE).flat_map(|scoop| scoop[hash_size..].iter().cloned()).collect()
+}
+
+/// New second hashes of a chunk of nonces in scoop `scoop_number` and its mirrored scoop
+struct Journal {
+    scoop_number: u16,
+    first_nonce_index: u64,
+    low_hashes: Vec<u8>,
+    high_hashes: Vec<u8>,
+}
+
+impl Journal {
+    const HEADER_SIZE: usize = 16;
+
+    fn nonces(&self) -> u64 {
+        (self.low_hashes.len() / HASH_SIZE as usize) as u64
+    }
+
+    fn load(path: &Path) -> Result<Option<Self>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let hashes_len = data.len().saturating_sub(Self::HEADER_SIZE) / 2;
+        if data.len() < Self::HEADER_SIZE || hashes_len == 0 || hashes_len % HASH_SIZE as usize != 0
+            || data.len() != Self::HEADER_SIZE + 2 * hashes_len {
+            return Err(err_msg(format!("Journal {} is corrupt", path.display())));
+        }
+
+        let hashes = &data[Self::HEADER_SIZE..];
+        Ok(Some(Self {
+            scoop_number: LittleEndian::read_u64(&data[0..8]) as u16,
+            first_nonce_index: LittleEndian::read_u64(&data[8..16]),
+            low_hashes: hashes[..hashes_len].to_vec(),
+            high_hashes: hashes[hashes_len..].to_vec(),
+        }))
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut data = vec![0u8; Self::HEADER_SIZE];
+        LittleEndian::write_u64(&mut data[0..8], self.scoop_number as u64);
+        LittleEndian::write_u64(&mut data[8..16], self.first_nonce_index);
+        data.extend_from_slice(&self.low_hashes);
+        data.extend_from_slice(&self.high_hashes);
+        write_atomically(path, &data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use plot::{PlotResult, scoop_offset};
+    use plot::cpu_nonce_computer::generate_nonce;
+    use plot::writer::PlotWriter;
+    use util::constants::GEN_SIZE;
+
+    fn poc1_plot(directory: &Path, nonces: u64) -> PathBuf {
+        fs::create_dir_all(directory).unwrap();
+        let mut data = vec![0u8; nonces as usize * GEN_SIZE];
+        for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+            generate_nonce(9, index as u64, PlotFormat::PoC1, nonce);
+        }
+        let mut writer = PlotWriter::create(directory, 9, 0, nonces, None, PlotFormat::PoC1, nonces).unwrap();
+        writer.write(&PlotResult::from_bytes(0, data, PlotFormat::PoC1).unwrap()).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn assert_poc2(path: &Path, nonces: u64) {
+        assert_eq!(path.file_name().unwrap().to_str().unwrap(), format!("9_0_{}", nonces));
+        let mut plot_file = PlotFile::open(path).unwrap();
+        let scoop_numbers = [0u16, 1, 2047, 2048, 4094, 4095];
+        let scoops: Vec<_> = scoop_numbers.iter().map(|&scoop_number| plot_file.read_scoops(scoop_number).unwrap()).collect();
+
+        let mut buffer = vec![0u8; GEN_SIZE];
+        for nonce_index in 0..nonces {
+            generate_nonce(9, nonce_index, PlotFormat::PoC2, &mut buffer);
+            for (&scoop_number, scoops) in scoop_numbers.iter().zip(&scoops) {
+                let offset = scoop_offset(scoop_number).unwrap();
+                assert_eq!(&scoops.scoop_data(nonce_index).unwrap().bytes()[..], &buffer[offset..offset + SCOOP_SIZE]);
+            }
+        }
+    }
+
+    #[test]
+    fn converts_in_chunks() {
+        let directory = env::temp_dir().join(format!("burst-rust-convert-{}", ::std::process::id()));
+        let path = poc1_plot(&directory, 5);
+
+        let converted = convert_to_poc2(&path, 2 * 2 * SCOOP_SIZE).unwrap();
+        assert_poc2(&converted, 5);
+        assert!(!path.exists());
+        assert!(!sidecar_path(&converted, "converting").exists());
+        assert!(convert_to_poc2(&converted, 1 << 20).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_staggered_files() {
+        let directory = env::temp_dir().join(format!("burst-rust-convert-staggered-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("9_0_4_2");
+        File::create(&path).unwrap().set_len(4 * ::util::constants::PLOT_SIZE as u64).unwrap();
+
+        let result = convert_to_poc2(&path, 1 << 20);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn finishes_conversion_renamed_before_journal_was_removed() {
+        let directory = env::temp_dir().join(format!("burst-rust-convert-renamed-{}", ::std::process::id()));
+        let path = poc1_plot(&directory, 4);
+
+        let destination = convert_to_poc2(&path, 1 << 20).unwrap();
+        let journal_path = sidecar_path(&destination, "converting");
+        fs::write(&journal_path, b"").unwrap();
+
+        // a rerun with the original name only removes the journal
+        let converted = convert_to_poc2(&path, 1 << 20).unwrap();
+        assert_eq!(converted, destination);
+        assert!(!journal_path.exists());
+        assert_poc2(&converted, 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn resumes_partially_written_chunk() {
+        let directory = env::temp_dir().join(format!("burst-rust-convert-resume-{}", ::std::process::id()));
+        let path = poc1_plot(&directory, 4);
+        let journal_path = directory.join("9_0_4.converting");
+
+        let mut conversion = Conversion {
+            file: OpenOptions::new().read(true).write(true).open(&path).unwrap(),
+            nonces: 4,
+            chunk_nonces: 2,
+            journal_path: journal_path.clone(),
+        };
+        for scoop_number in 0..3 {
+            conversion.convert_chunk(scoop_number, 0, 2).unwrap();
+            conversion.convert_chunk(scoop_number, 2, 2).unwrap();
+        }
+
+        // journal the next chunk but only write its low scoop, as if the process died in between
+        let low = conversion.read_region(3, 0, 2).unwrap();
+        let high = conversion.read_region(mirrored_scoop(3).unwrap(), 0, 2).unwrap();
+        let journal = Journal { scoop_number: 3, first_nonce_index: 0, low_hashes: second_hashes(&high), high_hashes: second_hashes(&low) };
+        journal.save(&journal_path).unwrap();
+        conversion.write_patched(3, 0, low, &journal.low_hashes).unwrap();
+
+        let converted = convert_to_poc2(&path, 2 * 2 * SCOOP_SIZE).unwrap();
+        assert_poc2(&converted, 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
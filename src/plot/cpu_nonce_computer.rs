@@ -0,0 +1,172 @@
+use byteorder::{ByteOrder, BigEndian};
+use failure::{Error, err_msg};
+use rayon;
+use rayon::prelude::*;
+
+use mine::MiningInfo;
+use plot::{Nonce, NonceComputer, PlotFormat, PlotResult, swap_poc2_hashes};
+use util::constants::{GEN_SIZE, PLOT_SIZE, HASH_SIZE, HASH_CAP};
+use util::sph_shabal::shabal256;
+
+/// Computes nonces on all CPU cores, producing the same `PlotResult` layout as the OpenCL kernels
+pub struct CpuNonceComputer {
+    address: u64,
+    format: PlotFormat,
+    nonces_per_batch: u64,
+    current_nonce: u64,
+}
+
+impl CpuNonceComputer {
+    pub fn new(address: u64, format: PlotFormat, nonces_per_batch: u64) -> Self {
+        Self { address, format, nonces_per_batch, current_nonce: 0 }
+    }
+}
+
+impl NonceComputer for CpuNonceComputer {
+    fn set_nonce(&mut self, nonce: u64) {
+        self.current_nonce = nonce;
+    }
+
+    fn current_nonce(&self) -> u64 {
+        self.current_nonce
+    }
+
+    fn batch_size(&self) -> u64 {
+        self.nonces_per_batch
+    }
+
+    fn device_name(&self) -> String {
+        format!("CPU ({} threads)", rayon::current_num_threads())
+    }
+
+    /// Compute the next nonces_per_batch many nonces
+    fn compute_next_nonces(&mut self) -> Result<PlotResult, Error> {
+        let start_nonce = self.current_nonce;
+        let address = self.address;
+        let format = self.format;
+
+        let mut vec = vec![0u8; self.nonces_per_batch as usize * GEN_SIZE];
+        vec.par_chunks_mut(GEN_SIZE)
+            .enumerate()
+            .for_each(|(index, nonce)| generate_nonce(address, start_nonce + index as u64, format, nonce));
+
+        self.current_nonce += self.nonces_per_batch;
+
+        PlotResult::from_bytes(start_nonce, vec, format)
+    }
+}
+
+/// Deadline of a single nonce for a block, computed without plot files or a GPU.
+///
+/// The nonce is generated in the plot format of the block's height, so deadlines claimed by miners
+/// can be double-checked.
+pub fn calculate_nonce_deadline(account_id: u64, nonce: u64, mining_info: &MiningInfo) -> Result<u64, Error> {
+    let mut buffer = vec![0u8; GEN_SIZE];
+    generate_nonce(account_id, nonce, mining_info.plot_format(), &mut buffer);
+    Nonce::from_slice(&buffer, mining_info.plot_format())
+        .ok_or_else(|| err_msg("Generated nonce is incomplete"))?
+        .calculate_deadline(mining_info)
+}
+
+/// Generate a single nonce into `buffer` (GEN_SIZE bytes): the plot data followed by the 16 byte seed
+pub fn generate_nonce(address: u64, nonce: u64, format: PlotFormat, buffer: &mut [u8]) {
+    assert_eq!(buffer.len(), GEN_SIZE);
+
+    BigEndian::write_u64(&mut buffer[PLOT_SIZE..PLOT_SIZE + 8], address);
+    BigEndian::write_u64(&mut buffer[PLOT_SIZE + 8..GEN_SIZE], nonce);
+
+    // every hash covers at most HASH_CAP bytes of its successors
+    let hash_size = HASH_SIZE as usize;
+    let mut offset = PLOT_SIZE;
+    while offset > 0 {
+        let len = ::std::cmp::min(GEN_SIZE - offset, HASH_CAP);
+        let hash = shabal256(&buffer[offset..offset + len]);
+        buffer[offset - hash_size..offset].copy_from_slice(&hash);
+        offset -= hash_size;
+    }
+
+    let final_hash = shabal256(buffer);
+    for (i, byte) in buffer[..PLOT_SIZE].iter_mut().enumerate() {
+        *byte ^= final_hash[i % hash_size];
+    }
+
+    if format == PlotFormat::PoC2 {
+        swap_poc2_hashes(&mut buffer[..PLOT_SIZE]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use plot::mirrored_scoop;
+    use util::constants::SCOOP_SIZE;
+    use util::sph_shabal::Shabal256;
+
+    const GENERATION_SIGNATURE: [u8; 32] = [233, 36, 246, 242, 87, 223, 13, 96, 189, 243, 238, 93, 70, 224, 34, 49,
+        217, 12, 178, 207, 182, 244, 24, 126, 226, 177, 148, 68, 138, 37, 253, 176];
+
+    #[test]
+    fn matches_opencl_deadlines() {
+        // values computed by the OpenCL kernels
+        let mut gensig_hasher = Shabal256::new();
+        gensig_hasher.update(&GENERATION_SIGNATURE);
+        let base_target = 43899;
+
+        let mut buffer = vec![0u8; GEN_SIZE];
+        let deadline = |buffer: &[u8], scoop| Nonce::from_slice(buffer, PlotFormat::PoC1).unwrap().scoop_data(scoop).unwrap()
+            .calculate_deadline_with_hasher(&gensig_hasher, base_target);
+
+        generate_nonce(11433454602339013530, 0, PlotFormat::PoC1, &mut buffer);
+        assert_eq!(deadline(&buffer, 0), 304653882166113, "Scoop 0 Nonce 0");
+        assert_eq!(deadline(&buffer, 1337), 282452543406894, "Scoop 1337 Nonce 0");
+
+        generate_nonce(11433454602339013530, 42, PlotFormat::PoC1, &mut buffer);
+        assert_eq!(deadline(&buffer, 0), 142426830646534, "Scoop 0 Nonce 42");
+        assert_eq!(deadline(&buffer, 1337), 146916916496699, "Scoop 1337 Nonce 42");
+    }
+
+    #[test]
+    fn calculates_deadline_of_single_nonce() {
+        // PoC1 height, values computed by the OpenCL kernels
+        let mining_info = MiningInfo::new(GENERATION_SIGNATURE.to_vec(), 465699, 43899, Some(1337));
+        assert_eq!(calculate_nonce_deadline(11433454602339013530, 42, &mining_info).unwrap(), 146916916496699);
+
+        // PoC2 combines the first hash of the scoop with the second hash of the mirrored PoC1 scoop
+        let mut poc1 = vec![0u8; GEN_SIZE];
+        generate_nonce(11433454602339013530, 42, PlotFormat::PoC1, &mut poc1);
+        let mut scoop = [0u8; SCOOP_SIZE];
+        let hash_size = HASH_SIZE as usize;
+        let low = 1337 * SCOOP_SIZE;
+        let high = mirrored_scoop(1337).unwrap() as usize * SCOOP_SIZE;
+        scoop[..hash_size].copy_from_slice(&poc1[low..low + hash_size]);
+        scoop[hash_size..].copy_from_slice(&poc1[high + hash_size..high + SCOOP_SIZE]);
+
+        let mining_info = MiningInfo::new(GENERATION_SIGNATURE.to_vec(), 600000, 43899, Some(1337));
+        let expected = ::plot::ScoopData::from_bytes(&scoop).calculate_deadline(&mining_info).unwrap();
+        assert_eq!(calculate_nonce_deadline(11433454602339013530, 42, &mining_info).unwrap(), expected);
+    }
+
+    #[test]
+    fn batch_keeps_seed_trailer() {
+        let mut computer = CpuNonceComputer::new(1, PlotFormat::PoC1, 2);
+        computer.set_nonce(5);
+
+        let plot = computer.compute_next_nonces().unwrap();
+
+        assert_eq!(plot.start_nonce(), 5);
+        assert_eq!(computer.current_nonce(), 7);
+        assert_eq!(plot.seed_by_index(0), Some((1, 5)));
+        assert_eq!(plot.seed_by_index(1), Some((1, 6)));
+    }
+
+    #[test]
+    fn poc2_nonce_is_shuffled_poc1_nonce() {
+        let mut poc1 = vec![0u8; GEN_SIZE];
+        let mut poc2 = vec![0u8; GEN_SIZE];
+        generate_nonce(7, 3, PlotFormat::PoC1, &mut poc1);
+        generate_nonce(7, 3, PlotFormat::PoC2, &mut poc2);
+
+        swap_poc2_hashes(&mut poc1[..PLOT_SIZE]);
+        assert!(poc1 == poc2);
+    }
+}
@@ -0,0 +1,241 @@
+use failure::{Error, err_msg};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+
+/// Offsets, lengths and buffers of O_DIRECT reads and writes are aligned to this size
+pub const SECTOR_SIZE: usize = 4096;
+
+/// A zeroed byte buffer that starts at a sector boundary
+pub struct AlignedBuffer {
+    data: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> Self {
+        let data = vec![0u8; len + SECTOR_SIZE];
+        let offset = (SECTOR_SIZE - data.as_ptr() as usize % SECTOR_SIZE) % SECTOR_SIZE;
+        Self { data, offset, len }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.len]
+    }
+}
+
+/// A file read and written at absolute offsets, bypassing the page cache once direct I/O is enabled.
+///
+/// Direct reads and writes are widened to whole sectors and go through an aligned buffer, so
+/// callers can use any offset and length. Unaligned writes read the partially covered sectors
+/// first. The buffered handle stays open for everything direct I/O can not do: growing the file
+/// past a sector boundary, and file systems that refuse O_DIRECT at open or on the first access.
+pub struct DirectFile {
+    file: File,
+    direct: Option<File>,
+    path: PathBuf,
+    writable: bool,
+}
+
+impl DirectFile {
+    /// Wrap a file opened for buffered I/O, `writable` has to match how `file` was opened
+    pub fn new<P: AsRef<Path>>(file: File, path: P, writable: bool) -> Self {
+        Self { file, direct: None, path: path.as_ref().to_path_buf(), writable }
+    }
+
+    /// The buffered handle, e.g. for syncing
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.direct.is_some()
+    }
+
+    /// Open the file a second time with O_DIRECT. Returns false and keeps using buffered I/O if
+    /// the file system does not support it.
+    pub fn enable_direct_io(&mut self) -> Result<bool, Error> {
+        if self.direct.is_none() {
+            match open_direct(&self.path, self.writable) {
+                Ok(file) => self.direct = file,
+                Err(ref e) if is_unsupported(e) => {},
+                Err(e) => return Err(err_msg(format!("Could not open {} for direct I/O: {}", self.path.display(), e))),
+            }
+        }
+        Ok(self.is_direct())
+    }
+
+    pub fn read_at(&mut self, offset: u64, data: &mut [u8]) -> Result<(), Error> {
+        if self.direct.is_some() && !data.is_empty() {
+            match self.read_direct(offset, data) {
+                Err(ref e) if is_unsupported(e) => self.direct = None,
+                result => return Ok(result?),
+            }
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(data)?;
+        Ok(())
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let (_, end) = aligned_range(offset, data.len());
+        if self.direct.is_some() && !data.is_empty() && end <= self.file.metadata()?.len() {
+            match self.write_direct(offset, data) {
+                Err(ref e) if is_unsupported(e) => self.direct = None,
+                result => return Ok(result?),
+            }
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn read_direct(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let (start, end) = aligned_range(offset, data.len());
+        let mut buffer = AlignedBuffer::new((end - start) as usize);
+        let needed = (offset - start) as usize + data.len();
+        let filled = read_sectors(self.direct.as_mut().unwrap(), start, &mut buffer)?;
+        if filled < needed {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+        }
+
+        let head = (offset - start) as usize;
+        data.copy_from_slice(&buffer[head..head + data.len()]);
+        Ok(())
+    }
+
+    fn write_direct(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (start, end) = aligned_range(offset, data.len());
+        let mut buffer = AlignedBuffer::new((end - start) as usize);
+        let head = (offset - start) as usize;
+        let tail = head + data.len();
+        let file = self.direct.as_mut().unwrap();
+
+        // keep the bytes of the first and last sector that are not overwritten
+        if head != 0 {
+            read_sectors(file, start, &mut buffer[..SECTOR_SIZE])?;
+        }
+        if tail % SECTOR_SIZE != 0 && (head == 0 || tail > SECTOR_SIZE) {
+            let last_sector = buffer.len() - SECTOR_SIZE;
+            read_sectors(file, start + last_sector as u64, &mut buffer[last_sector..])?;
+        }
+
+        buffer[head..tail].copy_from_slice(data);
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(&buffer)
+    }
+}
+
+/// Sector aligned byte range that covers `len` bytes at `offset`
+fn aligned_range(offset: u64, len: usize) -> (u64, u64) {
+    let sector_size = SECTOR_SIZE as u64;
+    let start = offset / sector_size * sector_size;
+    let end = (offset + len as u64 + sector_size - 1) / sector_size * sector_size;
+    (start, end)
+}
+
+/// Read whole sectors into `buffer` until it is full or the file ends, returns the bytes read
+fn read_sectors(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path, writable: bool) -> io::Result<Option<File>> {
+    use libc;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().read(true).write(writable).custom_flags(libc::O_DIRECT).open(path).map(Some)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path, _writable: bool) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+/// File systems like tmpfs reject O_DIRECT with EINVAL, either at open or on the first read or write
+#[cfg(target_os = "linux")]
+fn is_unsupported(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(::libc::EINVAL)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_unsupported(_error: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn aligns_buffers_and_ranges() {
+        for &len in &[1, SECTOR_SIZE, 3 * SECTOR_SIZE + 64] {
+            let buffer = AlignedBuffer::new(len);
+            assert_eq!(buffer.len(), len);
+            assert_eq!(buffer.as_ptr() as usize % SECTOR_SIZE, 0);
+        }
+        assert_eq!(aligned_range(0, SECTOR_SIZE), (0, 4096));
+        assert_eq!(aligned_range(64, 64), (0, 4096));
+        assert_eq!(aligned_range(4032, 128), (0, 8192));
+        assert_eq!(aligned_range(8192, 0), (8192, 8192));
+    }
+
+    #[test]
+    fn reads_and_writes_unaligned_ranges() {
+        let directory = env::temp_dir().join(format!("burst-rust-direct-io-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("data");
+        let mut expected: Vec<u8> = (0..4 * SECTOR_SIZE).map(|index| (index % 251) as u8).collect();
+        fs::write(&path, &expected).unwrap();
+
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut direct_file = DirectFile::new(file, &path, true);
+        direct_file.enable_direct_io().unwrap();
+
+        for &(offset, len) in &[(0, SECTOR_SIZE), (64, 64), (4032, 2 * SECTOR_SIZE), (3 * SECTOR_SIZE as u64 + 100, 50)] {
+            let data: Vec<u8> = (0..len).map(|index| (index % 7) as u8 + 1).collect();
+            direct_file.write_at(offset, &data).unwrap();
+            expected[offset as usize..offset as usize + len].copy_from_slice(&data);
+
+            let mut read = vec![0u8; len];
+            direct_file.read_at(offset, &mut read).unwrap();
+            assert_eq!(read, data);
+        }
+
+        // growing the file goes through the buffered handle
+        direct_file.write_at(expected.len() as u64 - 10, &[9u8; 20]).unwrap();
+        expected.truncate(expected.len() - 10);
+        expected.extend_from_slice(&[9u8; 20]);
+        let mut beyond_end = vec![0u8; 64];
+        assert!(direct_file.read_at(expected.len() as u64 - 32, &mut beyond_end).is_err());
+
+        direct_file.file().sync_all().unwrap();
+        let written = fs::read(&path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(written, expected);
+    }
+}
@@ -0,0 +1,289 @@
+use failure::{Error, err_msg};
+use std::cmp::min;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use plot::{PlotFormat, ScoopData};
+use plot::direct_io::DirectFile;
+use plot::progress::sidecar_path;
+use plot::writer::{file_offset, plot_file_name};
+use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE, PLOT_SIZE};
+
+/// Metadata encoded in a plot file name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlotFileInfo {
+    account_id: u64,
+    start_nonce: u64,
+    nonces: u64,
+    stagger: Option<u64>,
+}
+
+impl PlotFileInfo {
+    /// Parse `<accountId>_<startNonce>_<nonces>` or `<accountId>_<startNonce>_<nonces>_<stagger>`
+    pub fn parse(file_name: &str) -> Result<Self, Error> {
+        let parts = file_name.split('_')
+            .map(|part| part.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| err_msg(format!("{} is not a plot file name", file_name)))?;
+
+        let info = match parts.len() {
+            3 => Self { account_id: parts[0], start_nonce: parts[1], nonces: parts[2], stagger: None },
+            4 => Self { account_id: parts[0], start_nonce: parts[1], nonces: parts[2], stagger: Some(parts[3]) },
+            _ => return Err(err_msg(format!("{} is not a plot file name", file_name))),
+        };
+
+        if info.nonces == 0 || info.stagger() == 0 || info.nonces % info.stagger() != 0 {
+            return Err(err_msg(format!("Plot file {} has an invalid stagger", file_name)));
+        }
+        // the last nonce and the file size have to fit into 64 bits
+        if info.start_nonce.checked_add(info.nonces).is_none() || info.nonces.checked_mul(PLOT_SIZE as u64).is_none() {
+            return Err(err_msg(format!("Plot file {} has too many nonces", file_name)));
+        }
+        Ok(info)
+    }
+    pub fn account_id(&self) -> u64 {
+        self.account_id
+    }
+    pub fn start_nonce(&self) -> u64 {
+        self.start_nonce
+    }
+    pub fn nonces(&self) -> u64 {
+        self.nonces
+    }
+    /// Nonces per stagger group, the whole file for optimized plots
+    pub fn stagger(&self) -> u64 {
+        self.stagger.unwrap_or(self.nonces)
+    }
+    /// Legacy files carry a stagger in their name and are PoC1, files without one are PoC2
+    pub fn format(&self) -> PlotFormat {
+        match self.stagger {
+            Some(_) => PlotFormat::PoC1,
+            None => PlotFormat::PoC2,
+        }
+    }
+    pub fn file_name(&self) -> String {
+        plot_file_name(self.account_id, self.start_nonce, self.nonces, self.stagger)
+    }
+    /// Whether all nonces are in a single stagger group
+    pub fn is_optimized(&self) -> bool {
+        self.stagger() == self.nonces
+    }
+    /// The same plot with all nonces in a single stagger group
+    pub fn optimized(&self) -> Self {
+        let stagger = match self.format() {
+            PlotFormat::PoC1 => Some(self.nonces),
+            PlotFormat::PoC2 => None,
+        };
+        Self { stagger, ..*self }
+    }
+    /// The same plot in `format`. PoC2 files are always optimized and carry no stagger in their name.
+    pub fn with_format(&self, format: PlotFormat) -> Self {
+        let stagger = match format {
+            PlotFormat::PoC1 => Some(self.stagger()),
+            PlotFormat::PoC2 => None,
+        };
+        Self { stagger, ..*self }
+    }
+    /// Expected size of the file in bytes
+    pub fn file_size(&self) -> u64 {
+        self.nonces * PLOT_SIZE as u64
+    }
+}
+
+/// The scoops of one scoop number for all nonces of a plot file
+pub struct Scoops {
+    start_nonce: u64,
+    data: Vec<u8>,
+}
+
+impl Scoops {
+    pub fn start_nonce(&self) -> u64 {
+        self.start_nonce
+    }
+    pub fn num_nonces(&self) -> u64 {
+        (self.data.len() / SCOOP_SIZE) as u64
+    }
+    pub fn scoop_data(&self, nonce_index: u64) -> Option<ScoopData> {
+        if nonce_index >= self.num_nonces() {
+            return None;
+        }
+        let offset = nonce_index as usize * SCOOP_SIZE;
+        ScoopData::from_slice(&self.data[offset..offset + SCOOP_SIZE])
+    }
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+    /// Scoops in nonce order, starting at `start_nonce`
+    pub fn scoops(&self) -> impl Iterator<Item=ScoopData> {
+        self.data.chunks(SCOOP_SIZE).filter_map(ScoopData::from_slice)
+    }
+}
+
+/// An existing plot file opened for reading
+pub struct PlotFile {
+    file: DirectFile,
+    path: PathBuf,
+    info: PlotFileInfo,
+}
+
+impl PlotFile {
+    /// Open a plot file, checking that its size matches its name and that it is not being written
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let info = {
+            let file_name = path.file_name().and_then(|name| name.to_str())
+                .ok_or_else(|| err_msg(format!("{} is not a plot file", path.display())))?;
+            PlotFileInfo::parse(file_name)?
+        };
+        if sidecar_path(&path, "progress").exists() {
+            return Err(err_msg(format!("Plot file {} is incomplete", path.display())));
+        }
+        Self::open_as(path, info)
+    }
+    /// Open a file whose data is laid out as `info` describes, whatever its name. Used to check a
+    /// rewritten file before it is renamed.
+    pub fn open_as<P: AsRef<Path>>(path: P, info: PlotFileInfo) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size != info.file_size() {
+            return Err(err_msg(format!("Plot file {} has {} bytes, expected {}", path.display(), size, info.file_size())));
+        }
+
+        Ok(Self { file: DirectFile::new(file, &path, false), path, info })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn info(&self) -> &PlotFileInfo {
+        &self.info
+    }
+
+    /// Read the file with O_DIRECT so scanning does not evict the page cache. Returns false if the
+    /// file system does not support it and buffered reads are used instead.
+    pub fn enable_direct_io(&mut self) -> Result<bool, Error> {
+        self.file.enable_direct_io()
+    }
+
+    /// Read the scoop `scoop_number` of every nonce, one contiguous read per stagger group
+    pub fn read_scoops(&mut self, scoop_number: u16) -> Result<Scoops, Error> {
+        let nonces = self.info.nonces();
+        self.read_scoop_range(scoop_number, 0, nonces)
+    }
+
+    /// Read the scoop `scoop_number` of `nonces` nonces starting at the nonce with index `first_nonce_index`
+    pub fn read_scoop_range(&mut self, scoop_number: u16, first_nonce_index: u64, nonces: u64) -> Result<Scoops, Error> {
+        if scoop_number >= SCOOPS_PER_PLOT {
+            return Err(err_msg(format!("Scoop number {} is out of range", scoop_number)));
+        }
+        if first_nonce_index.checked_add(nonces).map_or(true, |end| end > self.info.nonces()) {
+            return Err(err_msg(format!("{} nonces starting at index {} are out of range for {}", nonces,
+                                       first_nonce_index, self.path.display())));
+        }
+        let stagger = self.info.stagger();
+        let mut data = vec![0u8; nonces as usize * SCOOP_SIZE];
+
+        let mut nonce_index = first_nonce_index;
+        let mut filled = 0;
+        while filled < data.len() {
+            // scoops are contiguous up to the end of the stagger group
+            let group_nonces = stagger - nonce_index % stagger;
+            let len = min(group_nonces as usize * SCOOP_SIZE, data.len() - filled);
+
+            self.file.read_at(file_offset(nonce_index, scoop_number, stagger), &mut data[filled..filled + len])?;
+
+            filled += len;
+            nonce_index += (len / SCOOP_SIZE) as u64;
+        }
+
+        Ok(Scoops { start_nonce: self.info.start_nonce() + first_nonce_index, data })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use plot::PlotResult;
+    use plot::writer::PlotWriter;
+    use util::constants::GEN_SIZE;
+
+    #[test]
+    fn parses_file_names() {
+        let optimized = PlotFileInfo::parse("123_8192_4096").unwrap();
+        assert_eq!(optimized.account_id(), 123);
+        assert_eq!(optimized.start_nonce(), 8192);
+        assert_eq!(optimized.nonces(), 4096);
+        assert_eq!(optimized.stagger(), 4096);
+        assert_eq!(optimized.format(), PlotFormat::PoC2);
+        assert_eq!(optimized.file_name(), "123_8192_4096");
+
+        let staggered = PlotFileInfo::parse("123_0_4096_1024").unwrap();
+        assert_eq!(staggered.stagger(), 1024);
+        assert_eq!(staggered.format(), PlotFormat::PoC1);
+        assert!(!staggered.is_optimized());
+        assert_eq!(staggered.optimized().file_name(), "123_0_4096_4096");
+        assert!(staggered.optimized().is_optimized());
+        assert_eq!(PlotFileInfo::parse("123_0_4096_4096").unwrap().with_format(PlotFormat::PoC2).file_name(), "123_0_4096");
+        assert_eq!(optimized.with_format(PlotFormat::PoC1).file_name(), "123_8192_4096_4096");
+
+        assert!(PlotFileInfo::parse("123_0").is_err());
+        assert!(PlotFileInfo::parse("123_0_4096.tmp").is_err());
+        assert!(PlotFileInfo::parse("123_0_4096_1000").is_err());
+        assert!(PlotFileInfo::parse("1_18446744073709551615_1").is_err());
+        assert!(PlotFileInfo::parse("1_0_18446744073709551615").is_err());
+        assert!(PlotFileInfo::parse("1_18446744073709551614_1").is_ok());
+    }
+
+    #[test]
+    fn reads_scoops_written_by_writer() {
+        let directory = env::temp_dir().join(format!("burst-rust-file-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut data = vec![0u8; 4 * GEN_SIZE];
+        for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+            for (scoop_number, scoop) in nonce[..PLOT_SIZE].chunks_mut(SCOOP_SIZE).enumerate() {
+                scoop[0] = index as u8;
+                scoop[1] = scoop_number as u8;
+            }
+        }
+        let mut writer = PlotWriter::create(&directory, 1, 20, 4, Some(2), PlotFormat::PoC1, 4).unwrap();
+        writer.write(&PlotResult::from_bytes(20, data, PlotFormat::PoC1).unwrap()).unwrap();
+        let path = writer.finish().unwrap();
+
+        let mut plot_file = PlotFile::open(&path).unwrap();
+        let scoops = plot_file.read_scoops(7).unwrap();
+        // a range crossing the end of the first stagger group
+        let range = plot_file.read_scoop_range(4095, 1, 2).unwrap();
+        assert!(plot_file.read_scoop_range(0, 3, 2).is_err());
+        assert!(plot_file.read_scoop_range(0, 1, u64::max_value()).is_err());
+        assert!(plot_file.read_scoops(4096).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(scoops.start_nonce(), 20);
+        assert_eq!(scoops.num_nonces(), 4);
+        for (index, scoop) in scoops.scoops().enumerate() {
+            assert_eq!(scoop.bytes()[0], index as u8);
+            assert_eq!(scoop.bytes()[1], 7);
+        }
+        assert!(scoops.scoop_data(4).is_none());
+
+        assert_eq!(range.start_nonce(), 21);
+        assert_eq!(range.scoop_data(0).unwrap().bytes()[0], 1);
+        assert_eq!(range.scoop_data(1).unwrap().bytes()[0], 2);
+        assert_eq!(range.scoop_data(1).unwrap().bytes()[1], 255);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let directory = env::temp_dir().join(format!("burst-rust-truncated-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("1_0_2");
+        File::create(&path).unwrap().set_len(PLOT_SIZE as u64).unwrap();
+
+        let result = PlotFile::open(&path);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use failure::Error;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use plot::file::{PlotFile, PlotFileInfo};
+
+/// Nonces of two plot files of the same account that are plotted twice
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    pub account_id: u64,
+    pub first: PathBuf,
+    pub second: PathBuf,
+    pub start_nonce: u64,
+    pub nonces: u64,
+}
+
+#[derive(Debug, Clone)]
+struct NonceRange {
+    path: PathBuf,
+    start_nonce: u64,
+    end_nonce: u64,
+}
+
+/// Plot files of all accounts, indexed by their nonce ranges.
+///
+/// The ranges of every account are kept sorted by start nonce, so overlaps and gaps are found
+/// with a single pass over the ranges of an account.
+#[derive(Debug, Clone, Default)]
+pub struct PlotInventory {
+    accounts: BTreeMap<u64, Vec<NonceRange>>,
+}
+
+impl PlotInventory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_plot_files(plot_files: &[PlotFile]) -> Self {
+        let mut inventory = Self::new();
+        for plot_file in plot_files {
+            inventory.insert(plot_file.path(), plot_file.info());
+        }
+        inventory
+    }
+
+    /// Index every file in `directories` with a plot file name, including incomplete plot files.
+    /// Directories that do not exist are skipped.
+    pub fn scan_directories<P: AsRef<Path>>(directories: &[P]) -> Result<Self, Error> {
+        let mut inventory = Self::new();
+        for directory in directories {
+            if !directory.as_ref().is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(directory)? {
+                let path = entry?.path();
+                let info = path.file_name().and_then(|name| name.to_str()).and_then(|name| PlotFileInfo::parse(name).ok());
+                if let Some(info) = info {
+                    inventory.insert(&path, &info);
+                }
+            }
+        }
+        Ok(inventory)
+    }
+
+    pub fn insert(&mut self, path: &Path, info: &PlotFileInfo) {
+        let ranges = self.accounts.entry(info.account_id()).or_insert_with(Vec::new);
+        let range = NonceRange {
+            path: path.to_path_buf(),
+            start_nonce: info.start_nonce(),
+            end_nonce: info.start_nonce() + info.nonces(),
+        };
+        let position = ranges.iter().position(|other| other.start_nonce > range.start_nonce).unwrap_or(ranges.len());
+        ranges.insert(position, range);
+    }
+
+    pub fn account_ids(&self) -> Vec<u64> {
+        self.accounts.keys().cloned().collect()
+    }
+
+    pub fn plot_files(&self) -> usize {
+        self.accounts.values().map(|ranges| ranges.len()).sum()
+    }
+
+    /// Nonces of all plot files, counting overlapping nonces once per file
+    pub fn total_nonces(&self) -> u64 {
+        self.accounts.values()
+            .flat_map(|ranges| ranges.iter())
+            .map(|range| range.end_nonce - range.start_nonce)
+            .sum()
+    }
+
+    /// Nonces of all plot files, counting overlapping nonces once
+    pub fn unique_nonces(&self) -> u64 {
+        self.accounts.keys().map(|&account_id| self.unique_nonces_of(account_id)).sum()
+    }
+
+    pub fn unique_nonces_of(&self, account_id: u64) -> u64 {
+        self.merged_ranges(account_id).iter().map(|&(start, end)| end - start).sum()
+    }
+
+    /// Every pair of plot files of the same account with common nonces
+    pub fn overlaps(&self) -> Vec<Overlap> {
+        let mut overlaps = Vec::new();
+        for (&account_id, ranges) in &self.accounts {
+            let mut active: Vec<&NonceRange> = Vec::new();
+            for range in ranges {
+                active.retain(|other| other.end_nonce > range.start_nonce);
+                for other in &active {
+                    overlaps.push(Overlap {
+                        account_id,
+                        first: other.path.clone(),
+                        second: range.path.clone(),
+                        start_nonce: range.start_nonce,
+                        nonces: min(other.end_nonce, range.end_nonce) - range.start_nonce,
+                    });
+                }
+                active.push(range);
+            }
+        }
+        overlaps
+    }
+
+    /// Lowest start nonce for a new plot file of `nonces` nonces that does not overlap an existing plot file of the account
+    pub fn next_free_start_nonce(&self, account_id: u64, nonces: u64) -> u64 {
+        let mut candidate = 0u64;
+        for (start, end) in self.merged_ranges(account_id) {
+            if start >= candidate.saturating_add(nonces) {
+                break;
+            }
+            candidate = max(candidate, end);
+        }
+        candidate
+    }
+
+    /// Nonce ranges of an account with overlapping and adjacent ranges merged
+    fn merged_ranges(&self, account_id: u64) -> Vec<(u64, u64)> {
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for range in self.accounts.get(&account_id).map(|ranges| &ranges[..]).unwrap_or(&[]) {
+            match merged.last_mut() {
+                Some(last) if range.start_nonce <= last.1 => last.1 = max(last.1, range.end_nonce),
+                _ => merged.push((range.start_nonce, range.end_nonce)),
+            }
+        }
+        merged
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+
+    fn inventory(file_names: &[&str]) -> PlotInventory {
+        let mut inventory = PlotInventory::new();
+        for file_name in file_names {
+            inventory.insert(Path::new(file_name), &PlotFileInfo::parse(file_name).unwrap());
+        }
+        inventory
+    }
+
+    #[test]
+    fn finds_overlaps() {
+        let inventory = inventory(&["1_0_100", "1_50_100_50", "1_60_10", "1_200_100", "2_0_100"]);
+
+        assert_eq!(inventory.overlaps(), vec![
+            Overlap { account_id: 1, first: PathBuf::from("1_0_100"), second: PathBuf::from("1_50_100_50"), start_nonce: 50, nonces: 50 },
+            Overlap { account_id: 1, first: PathBuf::from("1_0_100"), second: PathBuf::from("1_60_10"), start_nonce: 60, nonces: 10 },
+            Overlap { account_id: 1, first: PathBuf::from("1_50_100_50"), second: PathBuf::from("1_60_10"), start_nonce: 60, nonces: 10 },
+        ]);
+        assert_eq!(inventory.total_nonces(), 410);
+        assert_eq!(inventory.unique_nonces_of(1), 250);
+        assert_eq!(inventory.unique_nonces(), 350);
+        assert!(self::inventory(&["1_0_100", "1_100_100"]).overlaps().is_empty());
+    }
+
+    #[test]
+    fn suggests_free_start_nonces() {
+        let inventory = inventory(&["1_10_10", "1_20_10", "1_50_50"]);
+
+        assert_eq!(inventory.next_free_start_nonce(1, 10), 0);
+        assert_eq!(inventory.next_free_start_nonce(1, 11), 30);
+        assert_eq!(inventory.next_free_start_nonce(1, 20), 30);
+        assert_eq!(inventory.next_free_start_nonce(1, 21), 100);
+        assert_eq!(inventory.next_free_start_nonce(1, u64::max_value()), 100);
+        assert_eq!(inventory.next_free_start_nonce(2, 1000), 0);
+    }
+
+    #[test]
+    fn scans_directories() {
+        let directory = env::temp_dir().join(format!("burst-rust-inventory-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for file_name in &["1_0_10", "1_10_10", "1_10_10.progress", "notes.txt"] {
+            File::create(directory.join(file_name)).unwrap();
+        }
+
+        let inventory = PlotInventory::scan_directories(&[&directory, &directory.join("missing")]).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(inventory.plot_files(), 2);
+        assert_eq!(inventory.account_ids(), vec![1]);
+        assert_eq!(inventory.next_free_start_nonce(1, 5), 20);
+    }
+}
@@ -1,12 +1,76 @@
-use byteorder::{ByteOrder, LittleEndian};
-use failure::Error;
-use std::io::Write;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use failure::{Error, err_msg};
 
-use mine::MiningInfo;
-use util::sph_shabal;
-use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE, PLOT_SIZE, GEN_SIZE};
+use mine::{CancellationToken, MiningInfo};
+use util::sph_shabal::Shabal256;
+use util::simd_shabal;
+use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE, PLOT_SIZE, GEN_SIZE, HASH_SIZE};
+
+use std::sync::mpsc::{channel, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
 
 pub mod ocl_nonce_computer;
+pub mod ocl_device;
+pub mod ocl_program;
+pub mod ocl_tuning;
+pub mod cpu_nonce_computer;
+pub mod writer;
+pub mod file;
+pub mod optimizer;
+pub mod progress;
+pub mod converter;
+pub mod verify;
+pub mod inventory;
+pub mod direct_io;
+
+/// Arrangement of the scoop hashes within a nonce
+///
+/// PoC1 stores both hashes of a scoop next to each other as they are generated. PoC2 swaps the
+/// second hash of every scoop with the second hash of the mirrored scoop `4095 - n`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlotFormat {
+    PoC1,
+    PoC2,
+}
+
+impl Default for PlotFormat {
+    fn default() -> Self {
+        PlotFormat::PoC2
+    }
+}
+
+/// Byte offset of a scoop within a nonce, `None` for scoop numbers beyond the last scoop
+pub fn scoop_offset(scoop_number: u16) -> Option<usize> {
+    if scoop_number >= SCOOPS_PER_PLOT {
+        return None;
+    }
+    Some(scoop_number as usize * SCOOP_SIZE)
+}
+
+/// The scoop whose second hash trades places with `scoop_number` in PoC2, `None` for scoop numbers
+/// beyond the last scoop
+pub fn mirrored_scoop(scoop_number: u16) -> Option<u16> {
+    if scoop_number >= SCOOPS_PER_PLOT {
+        return None;
+    }
+    Some(SCOOPS_PER_PLOT - 1 - scoop_number)
+}
+
+/// Swap the second hash of every scoop with the one of its mirrored scoop.
+///
+/// The swap is its own inverse, so it converts a nonce from PoC1 to PoC2 and back.
+pub fn swap_poc2_hashes(nonce: &mut [u8]) {
+    let hash_size = HASH_SIZE as usize;
+    // scoop `n` of the first half pairs with the scoop as far from the end of the second half
+    let (low, high) = nonce[..PLOT_SIZE].split_at_mut(PLOT_SIZE / 2);
+    for (low_scoop, high_scoop) in low.chunks_mut(SCOOP_SIZE).zip(high.chunks_mut(SCOOP_SIZE).rev()) {
+        for (a, b) in low_scoop[hash_size..].iter_mut().zip(high_scoop[hash_size..].iter_mut()) {
+            ::std::mem::swap(a, b);
+        }
+    }
+}
 
 pub struct ScoopData<'a> {
     data: &'a [u8; SCOOP_SIZE],
@@ -16,90 +80,552 @@ impl<'a> ScoopData<'a> {
     pub fn from_bytes(data: &'a [u8; SCOOP_SIZE]) -> Self {
         Self { data }
     }
-    pub fn from_slice(data: &'a [u8]) -> Self {
-        Self { data: array_ref!(data, 0, SCOOP_SIZE) }
+    /// Use the first `SCOOP_SIZE` bytes of `data`, `None` if it is shorter
+    pub fn from_slice(data: &'a [u8]) -> Option<Self> {
+        if data.len() < SCOOP_SIZE {
+            return None;
+        }
+        Some(Self { data: array_ref!(data, 0, SCOOP_SIZE) })
     }
     pub fn calculate_deadline(&self, mining_info: &MiningInfo) -> Result<u64, Error> {
-        let mut input = [0u8; 32 + 32 + 32]; // gensig + scoop data
-
-        (&mut input[0..32]).write(&mining_info.generation_signature()[..])?;
-        (&mut input[32..96]).write(self.data)?;
-
-        let shabal = sph_shabal::shabal256(&input);
+        Ok(self.calculate_deadline_with_hasher(&mining_info.gensig_hasher(), mining_info.base_target()))
+    }
+    /// Calculate the deadline from a hasher that already absorbed the generation signature
+    pub fn calculate_deadline_with_hasher(&self, gensig_hasher: &Shabal256, base_target: u64) -> u64 {
+        let mut hasher = gensig_hasher.clone();
+        hasher.update(self.data);
+        let shabal = hasher.finalize();
 
         let target = LittleEndian::read_u64(&shabal[0..8]);
 
-        Ok(target / mining_info.base_target())
+        target / base_target
     }
     pub fn bytes(&self) -> &[u8; SCOOP_SIZE] {
         self.data
     }
 }
 
+/// Calculate the deadlines of many scoops at once, using SIMD where the CPU supports it
+pub fn calculate_deadlines(scoops: &[ScoopData], mining_info: &MiningInfo) -> Vec<u64> {
+    let scoops: Vec<&[u8; SCOOP_SIZE]> = scoops.iter().map(|scoop| scoop.bytes()).collect();
+    simd_shabal::calculate_deadlines(mining_info.generation_signature(), &scoops, mining_info.base_target())
+}
+
+/// The plot data of one nonce, without the 16 byte seed trailer that follows it in a `PlotResult`
 pub struct Nonce<'a> {
-    data: &'a [u8; PLOT_SIZE as usize]
+    data: &'a [u8; PLOT_SIZE as usize],
+    format: PlotFormat,
 }
 
 impl<'a> Nonce<'a> {
-    pub fn from_bytes(data: &'a [u8; PLOT_SIZE as usize]) -> Self {
-        Self { data }
+    pub fn from_bytes(data: &'a [u8; PLOT_SIZE as usize], format: PlotFormat) -> Self {
+        Self { data, format }
+    }
+    /// Use the first `PLOT_SIZE` bytes of `data`, `None` if it is shorter
+    pub fn from_slice(data: &'a [u8], format: PlotFormat) -> Option<Self> {
+        if data.len() < PLOT_SIZE {
+            return None;
+        }
+        Some(Self { data: array_ref!(data, 0, PLOT_SIZE), format })
     }
-    pub fn from_slice(data: &'a [u8]) -> Self {
-        Self { data: array_ref!(data, 0, PLOT_SIZE) }
+    pub fn format(&self) -> PlotFormat {
+        self.format
     }
-    pub fn scoop_data(&self, scoop_number: u16) -> ScoopData {
-        assert!(scoop_number < SCOOPS_PER_PLOT);
-        let offset = scoop_number as usize * SCOOP_SIZE;
+    pub fn bytes(&self) -> &'a [u8; PLOT_SIZE as usize] {
+        self.data
+    }
+    /// Scoop data as stored in this nonce's format, `None` for scoop numbers beyond the last scoop
+    pub fn scoop_data(&self, scoop_number: u16) -> Option<ScoopData<'a>> {
+        if scoop_number >= SCOOPS_PER_PLOT {
+            return None;
+        }
+        let offset = scoop_offset(scoop_number)?;
         ScoopData::from_slice(&self.data[offset..offset + SCOOP_SIZE])
     }
+    /// All scoops in scoop number order
+    pub fn scoops(&self) -> impl Iterator<Item=ScoopData<'a>> {
+        self.data.chunks(SCOOP_SIZE).filter_map(ScoopData::from_slice)
+    }
+    /// Deadline of the scoop of the block's scoop number
     pub fn calculate_deadline(&self, mining_info: &MiningInfo) -> Result<u64, Error> {
-        // TODO: move to mining package?
-        let scoop_data = self.scoop_data(1);
-        let mut input = [0u8; 32 + 32 + 32]; // gensig + scoop data
-
-        (&mut input[0..32]).write(&mining_info.generation_signature()[..])?;
-        (&mut input[32..96]).write(scoop_data.bytes())?;
-
-        let shabal = sph_shabal::shabal256(&input);
-
-        let target = LittleEndian::read_u64(&shabal[0..8]);
-
-        Ok(target / mining_info.base_target())
+        if self.format != mining_info.plot_format() {
+            return Err(err_msg(format!("{:?} nonces are not valid at height {}", self.format, mining_info.height())));
+        }
+        let scoop_number = mining_info.scoop_number();
+        self.scoop_data(scoop_number)
+            .ok_or_else(|| err_msg(format!("Scoop number {} is out of range", scoop_number)))?
+            .calculate_deadline(mining_info)
     }
 }
 
-/// A (unoptimized) plot consisting of nonces with scoop data
+/// Consecutive nonces as computed by a `NonceComputer`.
+///
+/// Every nonce takes `GEN_SIZE` bytes: its `PLOT_SIZE` bytes of plot data followed by the 16 byte
+/// seed (account id and nonce number, big endian) it was generated from.
 pub struct PlotResult {
     start_nonce: u64,
-    pub data: Vec<u8>, // FIXME
+    format: PlotFormat,
+    data: Vec<u8>,
 }
 
 impl PlotResult {
-    pub fn from_bytes(start_nonce: u64, data: Vec<u8>) -> Self {
-        assert_eq!(data.len() % GEN_SIZE, 0, "Plot size {} not a multiple of {}", data.len(), GEN_SIZE);
-        Self { start_nonce, data }
+    /// Fails if `data` does not consist of whole `GEN_SIZE` nonces
+    pub fn from_bytes(start_nonce: u64, data: Vec<u8>, format: PlotFormat) -> Result<Self, Error> {
+        if data.len() % GEN_SIZE != 0 {
+            return Err(err_msg(format!("Plot size {} not a multiple of {}", data.len(), GEN_SIZE)));
+        }
+        Ok(Self { start_nonce, format, data })
     }
     pub fn start_nonce(&self) -> u64 {
         self.start_nonce
     }
+    pub fn format(&self) -> PlotFormat {
+        self.format
+    }
+    /// Raw nonces including their seed trailers
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
     pub fn num_nonces(&self) -> u64 {
-        (self.data.len() / PLOT_SIZE) as u64
+        (self.data.len() / GEN_SIZE) as u64
     }
+    /// Nonce with the absolute nonce number `nonce_number`, `None` if it is not part of this result
     pub fn nonce_by_number(&self, nonce_number: u64) -> Option<Nonce> {
-        if nonce_number < self.start_nonce {
+        nonce_number.checked_sub(self.start_nonce).and_then(|nonce_index| self.nonce_by_index(nonce_index))
+    }
+    pub fn nonce_by_index(&self, nonce_index: u64) -> Option<Nonce> {
+        if nonce_index >= self.num_nonces() {
             return None;
         }
-
-        self.nonce_by_index(nonce_number - self.start_nonce)
+        let offset = nonce_index as usize * GEN_SIZE;
+        Nonce::from_slice(&self.data[offset..offset + GEN_SIZE], self.format)
     }
-    pub fn nonce_by_index(&self, nonce_index: u64) -> Option<Nonce> {
-        if nonce_index > self.num_nonces() {
+    /// Seed trailer of a nonce: account id and nonce number it was generated from
+    pub fn seed_by_index(&self, nonce_index: u64) -> Option<(u64, u64)> {
+        if nonce_index >= self.num_nonces() {
             return None;
         }
-        let offset = nonce_index as usize * GEN_SIZE as usize;
-        Some(Nonce::from_slice(&self.data[offset..offset + PLOT_SIZE]))
+        let offset = nonce_index as usize * GEN_SIZE + PLOT_SIZE;
+        Some((BigEndian::read_u64(&self.data[offset..offset + 8]), BigEndian::read_u64(&self.data[offset + 8..offset + 16])))
     }
     pub fn nonces(&self) -> impl Iterator<Item=Nonce> {
-        self.data.chunks(GEN_SIZE).map(|slice| Nonce::from_slice(slice))
+        let format = self.format;
+        self.data.chunks(GEN_SIZE).map(move |slice| Nonce { data: array_ref!(slice, 0, PLOT_SIZE), format })
+    }
+}
+
+/// Deadlines of consecutive nonces for one block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineResult {
+    start_nonce: u64,
+    height: u64,
+    deadlines: Vec<u64>,
+}
+
+impl DeadlineResult {
+    pub fn new(start_nonce: u64, height: u64, deadlines: Vec<u64>) -> Self {
+        Self { start_nonce, height, deadlines }
+    }
+    pub fn start_nonce(&self) -> u64 {
+        self.start_nonce
+    }
+    /// Height of the block the deadlines were computed for
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+    pub fn num_nonces(&self) -> u64 {
+        self.deadlines.len() as u64
+    }
+    pub fn deadlines(&self) -> &[u64] {
+        &self.deadlines
+    }
+    /// `(nonce, deadline)` pairs in nonce order
+    pub fn nonce_deadlines<'a>(&'a self) -> impl Iterator<Item=(u64, u64)> + 'a {
+        let start_nonce = self.start_nonce;
+        self.deadlines.iter().enumerate().map(move |(index, &deadline)| (start_nonce + index as u64, deadline))
+    }
+    /// Nonce with the lowest deadline, the first one if several are equal
+    pub fn best(&self) -> Option<(u64, u64)> {
+        self.nonce_deadlines().min_by_key(|&(_, deadline)| deadline)
+    }
+}
+
+/// A backend computing batches of consecutive nonces
+pub trait NonceComputer {
+    /// Set the first nonce of the next batch
+    fn set_nonce(&mut self, nonce: u64);
+    fn current_nonce(&self) -> u64;
+    /// Compute the next `batch_size` nonces, starting at the current nonce
+    fn compute_next_nonces(&mut self) -> Result<PlotResult, Error>;
+    /// Number of nonces computed per batch
+    fn batch_size(&self) -> u64;
+    /// Human readable name of the device the nonces are computed on
+    fn device_name(&self) -> String;
+    /// Compute the next `batch_size` nonces like `compute_next_nonces`, but only return their
+    /// deadlines for a block. Backends that can hash the scoops where the nonces are computed
+    /// avoid transferring the full nonces.
+    fn compute_next_deadlines(&mut self, mining_info: &MiningInfo) -> Result<DeadlineResult, Error> {
+        let plot = self.compute_next_nonces()?;
+        let scoop_number = mining_info.scoop_number();
+        let scoops = plot.nonces()
+            .map(|nonce| nonce.scoop_data(scoop_number).ok_or_else(|| err_msg(format!("Scoop number {} is out of range", scoop_number))))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(DeadlineResult::new(plot.start_nonce(), mining_info.height(), calculate_deadlines(&scoops, mining_info)))
+    }
+}
+
+impl<C: NonceComputer + ?Sized> NonceComputer for Box<C> {
+    fn set_nonce(&mut self, nonce: u64) {
+        (**self).set_nonce(nonce)
+    }
+    fn current_nonce(&self) -> u64 {
+        (**self).current_nonce()
+    }
+    fn compute_next_nonces(&mut self) -> Result<PlotResult, Error> {
+        (**self).compute_next_nonces()
+    }
+    fn batch_size(&self) -> u64 {
+        (**self).batch_size()
+    }
+    fn device_name(&self) -> String {
+        (**self).device_name()
+    }
+    fn compute_next_deadlines(&mut self, mining_info: &MiningInfo) -> Result<DeadlineResult, Error> {
+        (**self).compute_next_deadlines(mining_info)
+    }
+}
+
+/// Keep computing batches on a separate thread. A nonce received on `receiver` restarts the computation there.
+pub fn continuous_nonce_computer<C>(mut nonce_computer: C, receiver: Receiver<u64>, sender: SyncSender<PlotResult>)
+    where C: NonceComputer + Send + 'static {
+    thread::spawn(move || {
+        loop {
+            if let Ok(nonce) = receiver.try_recv() {
+                nonce_computer.set_nonce(nonce);
+            }
+
+            let next_plot = nonce_computer.compute_next_nonces().expect("computing nonces failed");
+            sender.send(next_plot).expect("sending new nonces failed");
+        }
+    });
+
+}
+
+/// Nonces reserved for every nonce computer of `parallel_deadline_computer`
+pub const DEVICE_NONCE_RANGE: u64 = 1 << 48;
+
+/// Keep computing the deadlines of batches for the latest block received on `blocks` on a separate
+/// thread, starting at nonce 0 for every block. A batch finished after the token of its block was
+/// cancelled is dropped. Stops once either channel is closed.
+pub fn continuous_deadline_computer<C>(nonce_computer: C, blocks: Receiver<(MiningInfo, CancellationToken)>,
+                                       sender: SyncSender<DeadlineResult>)
+    where C: NonceComputer + Send + 'static {
+    thread::spawn(move || compute_deadlines(nonce_computer, 0, blocks, |deadlines| sender.send(deadlines).is_ok()));
+}
+
+/// Keep computing deadlines for the latest block received on `blocks` on all nonce computers in
+/// parallel, one thread each. For every block computer `i` starts at nonce `i * DEVICE_NONCE_RANGE`,
+/// so no nonce is computed twice. The results of all computers are sent on `sender` together with
+/// the index of their computer, batches of cancelled blocks are dropped.
+pub fn parallel_deadline_computer<C>(nonce_computers: Vec<C>, blocks: Receiver<(MiningInfo, CancellationToken)>,
+                                     sender: SyncSender<(usize, DeadlineResult)>)
+    where C: NonceComputer + Send + 'static {
+    let mut computer_blocks = Vec::with_capacity(nonce_computers.len());
+    for (index, nonce_computer) in nonce_computers.into_iter().enumerate() {
+        let (blocks_tx, blocks_rx) = channel();
+        let sender = sender.clone();
+        computer_blocks.push(blocks_tx);
+        thread::spawn(move || compute_deadlines(nonce_computer, index as u64 * DEVICE_NONCE_RANGE, blocks_rx,
+                                                |deadlines| sender.send((index, deadlines)).is_ok()));
+    }
+
+    thread::spawn(move || {
+        for block in blocks {
+            for blocks_tx in &computer_blocks {
+                // a computer whose results are no longer received has stopped already
+                blocks_tx.send(block.clone()).ok();
+            }
+        }
+    });
+}
+
+/// Compute deadlines until `send` returns false, restarting at `first_nonce` for every block.
+/// Once the token of the current block is cancelled nothing is computed until the next block.
+fn compute_deadlines<C, F>(mut nonce_computer: C, first_nonce: u64, blocks: Receiver<(MiningInfo, CancellationToken)>, send: F)
+    where C: NonceComputer, F: Fn(DeadlineResult) -> bool {
+    let (mut mining_info, mut cancellation) = match blocks.recv() {
+        Ok(block) => block,
+        Err(_) => return,
+    };
+    nonce_computer.set_nonce(first_nonce);
+    loop {
+        let next_block = if cancellation.is_cancelled() {
+            match blocks.recv() {
+                Ok(block) => Some(blocks.try_iter().last().unwrap_or(block)),
+                Err(_) => return,
+            }
+        } else {
+            blocks.try_iter().last()
+        };
+        if let Some((next_mining_info, next_cancellation)) = next_block {
+            mining_info = next_mining_info;
+            cancellation = next_cancellation;
+            nonce_computer.set_nonce(first_nonce);
+        }
+
+        let deadlines = nonce_computer.compute_next_deadlines(&mining_info).expect("computing deadlines failed");
+        if cancellation.is_cancelled() {
+            // the block is outdated, its deadlines would only be discarded by the receiver
+            continue;
+        }
+        if !send(deadlines) {
+            return;
+        }
+    }
+}
+
+/// Speed of computing `nonces` nonces in `duration`, 0 for an empty duration
+pub fn nonces_per_minute(nonces: u64, duration: Duration) -> f64 {
+    let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+    if seconds > 0.0 {
+        nonces as f64 * 60.0 / seconds
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+    use std::sync::mpsc::sync_channel;
+
+    /// Nonces whose plot data bytes are the nonce index + 1, followed by the seed trailer of account 7
+    fn marked_plot(start_nonce: u64, nonces: u64) -> PlotResult {
+        let mut data = vec![0u8; nonces as usize * GEN_SIZE];
+        for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+            for byte in nonce[..PLOT_SIZE].iter_mut() {
+                *byte = index as u8 + 1;
+            }
+            BigEndian::write_u64(&mut nonce[PLOT_SIZE..PLOT_SIZE + 8], 7);
+            BigEndian::write_u64(&mut nonce[PLOT_SIZE + 8..], start_nonce + index as u64);
+        }
+        PlotResult::from_bytes(start_nonce, data, PlotFormat::PoC2).unwrap()
+    }
+
+    fn mining_info(height: u64, scoop_number: u16) -> MiningInfo {
+        serde_json::from_str(&format!(r#"{{"generationSignature": "{}", "height": "{}", "baseTarget": "43899", "scoopNumber": {}}}"#,
+                                      "e924f6f257df0d60bdf3ee5d46e02231d90cb2cfb6f4187ee2b194448a25fdb0", height, scoop_number)).unwrap()
+    }
+
+    struct FakeNonceComputer {
+        current_nonce: u64,
+    }
+
+    impl NonceComputer for FakeNonceComputer {
+        fn set_nonce(&mut self, nonce: u64) {
+            self.current_nonce = nonce;
+        }
+        fn current_nonce(&self) -> u64 {
+            self.current_nonce
+        }
+        fn compute_next_nonces(&mut self) -> Result<PlotResult, Error> {
+            let start_nonce = self.current_nonce;
+            self.current_nonce += self.batch_size();
+            PlotResult::from_bytes(start_nonce, vec![0u8; self.batch_size() as usize * GEN_SIZE], PlotFormat::PoC1)
+        }
+        fn batch_size(&self) -> u64 {
+            2
+        }
+        fn device_name(&self) -> String {
+            String::from("fake")
+        }
+    }
+
+    #[test]
+    fn continuous_nonce_computer_restarts_at_received_nonce() {
+        let (nonces_tx, nonces_rx) = sync_channel(0);
+        let (nonces_idx_tx, nonces_idx_rx) = channel();
+        continuous_nonce_computer(FakeNonceComputer { current_nonce: 0 }, nonces_idx_rx, nonces_tx);
+
+        assert_eq!(nonces_rx.recv().unwrap().start_nonce(), 0);
+        assert_eq!(nonces_rx.recv().unwrap().start_nonce(), 2);
+
+        nonces_idx_tx.send(100).unwrap();
+        // one batch may already have been computed before the restart
+        let next = nonces_rx.recv().unwrap().start_nonce();
+        let restarted = if next == 100 { next } else { nonces_rx.recv().unwrap().start_nonce() };
+        assert_eq!(restarted, 100);
+        assert_eq!(nonces_rx.recv().unwrap().start_nonce(), 102);
+    }
+
+    #[test]
+    fn computes_deadlines_of_next_nonces() {
+        let mining_info = mining_info(600000, 17);
+        let mut nonce_computer = FakeNonceComputer { current_nonce: 4 };
+
+        let result = nonce_computer.compute_next_deadlines(&mining_info).unwrap();
+        let zeros = [0u8; SCOOP_SIZE];
+        let deadline = ScoopData::from_bytes(&zeros).calculate_deadline(&mining_info).unwrap();
+
+        assert_eq!(nonce_computer.current_nonce(), 6);
+        assert_eq!(result.height(), 600000);
+        assert_eq!(result.nonce_deadlines().collect::<Vec<_>>(), vec![(4, deadline), (5, deadline)]);
+        assert_eq!(result.best(), Some((4, deadline)));
+        assert_eq!(DeadlineResult::new(0, 1, vec![5, 3, 3, 9]).best(), Some((1, 3)));
+        assert_eq!(DeadlineResult::new(0, 1, Vec::new()).best(), None);
+    }
+
+    #[test]
+    fn continuous_deadline_computer_restarts_for_new_blocks() {
+        let (deadlines_tx, deadlines_rx) = sync_channel(0);
+        let (blocks_tx, blocks_rx) = channel();
+        blocks_tx.send((mining_info(600000, 0), CancellationToken::new())).unwrap();
+        continuous_deadline_computer(FakeNonceComputer { current_nonce: 100 }, blocks_rx, deadlines_tx);
+
+        let first = deadlines_rx.recv().unwrap();
+        assert_eq!((first.start_nonce(), first.height()), (0, 600000));
+        assert_eq!(deadlines_rx.recv().unwrap().start_nonce(), 2);
+
+        blocks_tx.send((mining_info(600001, 0), CancellationToken::new())).unwrap();
+        // one batch may already have been computed for the previous block
+        let next = deadlines_rx.recv().unwrap();
+        let restarted = if next.height() == 600001 { next } else { deadlines_rx.recv().unwrap() };
+        assert_eq!((restarted.start_nonce(), restarted.height()), (0, 600001));
+    }
+
+    #[test]
+    fn parallel_deadline_computer_uses_disjoint_nonce_ranges() {
+        let (deadlines_tx, deadlines_rx) = sync_channel(0);
+        let (blocks_tx, blocks_rx) = channel();
+        blocks_tx.send((mining_info(600000, 0), CancellationToken::new())).unwrap();
+        let nonce_computers = vec![FakeNonceComputer { current_nonce: 0 }, FakeNonceComputer { current_nonce: 0 }];
+        parallel_deadline_computer(nonce_computers, blocks_rx, deadlines_tx);
+
+        let mut start_nonces = vec![Vec::new(), Vec::new()];
+        while start_nonces.iter().any(|nonces| nonces.len() < 2) {
+            let (index, deadlines) = deadlines_rx.recv().unwrap();
+            start_nonces[index].push(deadlines.start_nonce());
+        }
+        assert_eq!(&start_nonces[0][..2], &[0, 2]);
+        assert_eq!(&start_nonces[1][..2], &[DEVICE_NONCE_RANGE, DEVICE_NONCE_RANGE + 2]);
+
+        blocks_tx.send((mining_info(600001, 0), CancellationToken::new())).unwrap();
+        let mut restarted = vec![None, None];
+        while restarted.iter().any(|nonce| nonce.is_none()) {
+            let (index, deadlines) = deadlines_rx.recv().unwrap();
+            if deadlines.height() == 600001 && restarted[index].is_none() {
+                restarted[index] = Some(deadlines.start_nonce());
+            }
+        }
+        assert_eq!(restarted, vec![Some(0), Some(DEVICE_NONCE_RANGE)]);
+    }
+
+    #[test]
+    fn deadline_computer_drops_cancelled_blocks() {
+        let (deadlines_tx, deadlines_rx) = sync_channel(0);
+        let (blocks_tx, blocks_rx) = channel();
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        blocks_tx.send((mining_info(600000, 0), cancellation)).unwrap();
+        continuous_deadline_computer(FakeNonceComputer { current_nonce: 100 }, blocks_rx, deadlines_tx);
+
+        assert!(deadlines_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        blocks_tx.send((mining_info(600001, 0), CancellationToken::new())).unwrap();
+        let next = deadlines_rx.recv().unwrap();
+        assert_eq!((next.start_nonce(), next.height()), (0, 600001));
+    }
+
+    #[test]
+    fn measures_nonces_per_minute() {
+        assert_eq!(nonces_per_minute(8192, Duration::from_secs(30)), 16384.0);
+        assert_eq!(nonces_per_minute(100, Duration::from_millis(1500)), 4000.0);
+        assert_eq!(nonces_per_minute(100, Duration::from_secs(0)), 0.0);
+    }
+
+    #[test]
+    fn poc2_swaps_second_hash_with_mirrored_scoop() {
+        let mut poc1 = vec![0u8; PLOT_SIZE];
+        for (i, byte) in poc1.iter_mut().enumerate() {
+            *byte = (i / HASH_SIZE as usize) as u8;
+        }
+        let mut poc2 = poc1.clone();
+        swap_poc2_hashes(&mut poc2);
+
+        let poc1_nonce = Nonce::from_slice(&poc1, PlotFormat::PoC1).unwrap();
+        let poc2_nonce = Nonce::from_slice(&poc2, PlotFormat::PoC2).unwrap();
+        for &scoop_number in &[0, 1, 2047, 2048, 4095] {
+            let poc1_scoop = poc1_nonce.scoop_data(scoop_number).unwrap();
+            let mirrored = poc1_nonce.scoop_data(mirrored_scoop(scoop_number).unwrap()).unwrap();
+            let poc2_scoop = poc2_nonce.scoop_data(scoop_number).unwrap();
+
+            assert_eq!(&poc2_scoop.bytes()[..32], &poc1_scoop.bytes()[..32]);
+            assert_eq!(&poc2_scoop.bytes()[32..], &mirrored.bytes()[32..]);
+        }
+
+        swap_poc2_hashes(&mut poc2);
+        assert!(poc1 == poc2);
+    }
+
+    #[test]
+    fn plot_result_skips_seed_trailers() {
+        let plot = marked_plot(10, 3);
+
+        assert_eq!(plot.num_nonces(), 3);
+        assert_eq!(plot.nonces().count(), 3);
+        for (index, nonce) in plot.nonces().enumerate() {
+            assert!(nonce.bytes().iter().all(|&byte| byte == index as u8 + 1));
+        }
+        let last = plot.nonce_by_index(2).unwrap();
+        assert_eq!(last.bytes()[0], 3);
+        assert_eq!(last.bytes()[PLOT_SIZE - 1], 3);
+        assert_eq!(plot.seed_by_index(2), Some((7, 12)));
+
+        assert!(plot.nonce_by_index(3).is_none());
+        assert!(plot.seed_by_index(3).is_none());
+        assert!(plot.nonce_by_number(9).is_none());
+        assert_eq!(plot.nonce_by_number(12).unwrap().bytes()[0], 3);
+        assert!(plot.nonce_by_number(13).is_none());
+        assert!(PlotResult::from_bytes(0, Vec::new(), PlotFormat::PoC2).unwrap().nonce_by_index(0).is_none());
+        assert!(PlotResult::from_bytes(0, vec![0u8; GEN_SIZE + 1], PlotFormat::PoC2).is_err());
+    }
+
+    #[test]
+    fn nonce_rejects_out_of_range_scoops() {
+        let data = vec![0u8; PLOT_SIZE];
+        let nonce = Nonce::from_slice(&data, PlotFormat::PoC2).unwrap();
+
+        assert!(nonce.scoop_data(SCOOPS_PER_PLOT - 1).is_some());
+        assert!(nonce.scoop_data(SCOOPS_PER_PLOT).is_none());
+        assert_eq!(nonce.scoops().count(), SCOOPS_PER_PLOT as usize);
+        assert!(Nonce::from_slice(&data[1..], PlotFormat::PoC2).is_none());
+        assert!(ScoopData::from_slice(&data[..SCOOP_SIZE - 1]).is_none());
+
+        assert_eq!(scoop_offset(SCOOPS_PER_PLOT - 1), Some(PLOT_SIZE - SCOOP_SIZE));
+        assert_eq!(scoop_offset(SCOOPS_PER_PLOT), None);
+        assert_eq!(mirrored_scoop(0), Some(SCOOPS_PER_PLOT - 1));
+        assert_eq!(mirrored_scoop(SCOOPS_PER_PLOT), None);
+    }
+
+    #[test]
+    fn nonce_deadline_uses_scoop_of_block() {
+        let mut data = vec![0u8; PLOT_SIZE];
+        for (scoop_number, scoop) in data.chunks_mut(SCOOP_SIZE).enumerate() {
+            scoop[0] = scoop_number as u8;
+            scoop[1] = (scoop_number >> 8) as u8;
+        }
+        let nonce = Nonce::from_slice(&data, PlotFormat::PoC2).unwrap();
+
+        for &scoop_number in &[0u16, 1, 1337, 4095] {
+            let mining_info = mining_info(600000, scoop_number);
+            let expected = nonce.scoop_data(scoop_number).unwrap().calculate_deadline(&mining_info).unwrap();
+            assert_eq!(nonce.calculate_deadline(&mining_info).unwrap(), expected);
+        }
+        assert_ne!(nonce.calculate_deadline(&mining_info(600000, 0)).unwrap(),
+                   nonce.calculate_deadline(&mining_info(600000, 1)).unwrap());
+
+        assert!(nonce.calculate_deadline(&mining_info(400000, 0)).is_err());
+        assert!(nonce.calculate_deadline(&mining_info(600000, 4096)).is_err());
     }
 }
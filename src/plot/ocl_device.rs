@@ -0,0 +1,187 @@
+use ocl::{Platform, Device};
+use ocl::enums::{DeviceInfo, DeviceInfoResult};
+use failure::{Error, err_msg};
+
+use util::config::DeviceConfig;
+use util::constants::GEN_SIZE;
+
+/// An OpenCL device together with the indices that select it in the device config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OclDeviceInfo {
+    pub platform_id: u32,
+    pub device_id: u32,
+    pub platform_name: String,
+    pub name: String,
+    pub vendor: String,
+    /// OpenCL version of the device
+    pub version: String,
+    pub driver_version: String,
+    pub compute_units: u32,
+    pub global_memory: u64,
+    pub local_memory: u64,
+    pub max_allocation: u64,
+    pub max_work_group_size: usize,
+}
+
+impl OclDeviceInfo {
+    /// Largest `global_work_size` whose `buffers` batches fit into the memory of the device
+    pub fn max_global_work_size(&self, buffers: usize) -> u64 {
+        // every batch needs a nonce buffer and a deadline buffer
+        let by_memory = self.global_memory / (buffers.max(1) as u64 * (GEN_SIZE as u64 + 8));
+        let by_allocation = self.max_allocation / GEN_SIZE as u64;
+        // the kernels address the nonce buffer with 32 bit offsets
+        let by_offset = u32::max_value() as u64 / GEN_SIZE as u64;
+        by_memory.min(by_allocation).min(by_offset)
+    }
+
+    /// Check that the work sizes and buffers of `device_config` can be used on this device
+    pub fn validate(&self, device_config: &DeviceConfig) -> Result<(), Error> {
+        let global_work_size = device_config.global_work_size() as u64;
+        let local_work_size = device_config.local_work_size() as u64;
+
+        if local_work_size == 0 || local_work_size > self.max_work_group_size as u64 {
+            return Err(err_msg(format!("local_work_size {} of {} has to be between 1 and {}",
+                                       local_work_size, self.name, self.max_work_group_size)));
+        }
+        if global_work_size == 0 || global_work_size % local_work_size != 0 {
+            return Err(err_msg(format!("global_work_size {} of {} has to be a multiple of local_work_size {}",
+                                       global_work_size, self.name, local_work_size)));
+        }
+        if device_config.buffers() == 0 {
+            return Err(err_msg(format!("buffers of {} has to be at least 1", self.name)));
+        }
+        let max_global_work_size = self.max_global_work_size(device_config.buffers());
+        if global_work_size > max_global_work_size {
+            return Err(err_msg(format!("global_work_size {} of {} exceeds the memory of the device, {} buffers fit at most {} nonces",
+                                       global_work_size, self.name, device_config.buffers(), max_global_work_size)));
+        }
+        Ok(())
+    }
+}
+
+/// Every device of every OpenCL platform
+pub fn list_devices() -> Result<Vec<OclDeviceInfo>, Error> {
+    let mut devices = Vec::new();
+    for (platform_id, platform) in Platform::list().into_iter().enumerate() {
+        for (device_id, device) in Device::list_all(platform.clone())?.into_iter().enumerate() {
+            devices.push(device_info(platform_id as u32, device_id as u32, &platform, &device)?);
+        }
+    }
+    Ok(devices)
+}
+
+/// The platform and device selected by `platform_id` and `device_id`
+pub fn find_device(platform_id: u32, device_id: u32) -> Result<(Platform, Device), Error> {
+    let platforms = Platform::list();
+    let platform = *platforms.get(platform_id as usize)
+        .ok_or_else(|| err_msg(format!("OpenCL platform {} not found, there are {} platforms", platform_id, platforms.len())))?;
+    let devices = Device::list_all(platform.clone())?;
+    let device = *devices.get(device_id as usize)
+        .ok_or_else(|| err_msg(format!("OpenCL device {} not found on platform {}, it has {} devices",
+                                       device_id, platform_id, devices.len())))?;
+    Ok((platform, device))
+}
+
+pub fn device_info(platform_id: u32, device_id: u32, platform: &Platform, device: &Device) -> Result<OclDeviceInfo, Error> {
+    let version = match query(device, DeviceInfo::Version)? {
+        DeviceInfoResult::Version(version) => version,
+        other => return Err(unexpected(other)),
+    };
+    let driver_version = match query(device, DeviceInfo::DriverVersion)? {
+        DeviceInfoResult::DriverVersion(driver_version) => driver_version,
+        other => return Err(unexpected(other)),
+    };
+    let compute_units = match query(device, DeviceInfo::MaxComputeUnits)? {
+        DeviceInfoResult::MaxComputeUnits(compute_units) => compute_units,
+        other => return Err(unexpected(other)),
+    };
+    let global_memory = match query(device, DeviceInfo::GlobalMemSize)? {
+        DeviceInfoResult::GlobalMemSize(global_memory) => global_memory,
+        other => return Err(unexpected(other)),
+    };
+    let local_memory = match query(device, DeviceInfo::LocalMemSize)? {
+        DeviceInfoResult::LocalMemSize(local_memory) => local_memory,
+        other => return Err(unexpected(other)),
+    };
+    let max_allocation = match query(device, DeviceInfo::MaxMemAllocSize)? {
+        DeviceInfoResult::MaxMemAllocSize(max_allocation) => max_allocation,
+        other => return Err(unexpected(other)),
+    };
+    let max_work_group_size = match query(device, DeviceInfo::MaxWorkGroupSize)? {
+        DeviceInfoResult::MaxWorkGroupSize(max_work_group_size) => max_work_group_size,
+        other => return Err(unexpected(other)),
+    };
+
+    Ok(OclDeviceInfo {
+        platform_id,
+        device_id,
+        platform_name: platform.name(),
+        name: device.name(),
+        vendor: device.vendor(),
+        version,
+        driver_version,
+        compute_units,
+        global_memory,
+        local_memory,
+        max_allocation,
+        max_work_group_size,
+    })
+}
+
+fn query(device: &Device, info: DeviceInfo) -> Result<DeviceInfoResult, Error> {
+    match device.info(info) {
+        DeviceInfoResult::Error(e) => Err(err_msg(format!("Could not query {:?} of {}: {}", info, device.name(), e))),
+        result => Ok(result),
+    }
+}
+
+fn unexpected(result: DeviceInfoResult) -> Error {
+    err_msg(format!("Unexpected device info {:?}", result))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+
+    fn device_config(global_work_size: u32, local_work_size: u32, buffers: usize) -> DeviceConfig {
+        serde_json::from_str(&format!(r#"{{"global_work_size": {}, "local_work_size": {}, "buffers": {}}}"#,
+                                      global_work_size, local_work_size, buffers)).unwrap()
+    }
+
+    #[test]
+    fn validates_device_configs() {
+        let device = OclDeviceInfo {
+            platform_id: 0,
+            device_id: 0,
+            platform_name: String::from("platform"),
+            name: String::from("device"),
+            vendor: String::from("vendor"),
+            version: String::from("OpenCL 1.2"),
+            driver_version: String::from("390.48"),
+            compute_units: 20,
+            global_memory: 8 * 1024 * 1024 * 1024,
+            local_memory: 48 * 1024,
+            max_allocation: 4 * 1024 * 1024 * 1024,
+            max_work_group_size: 256,
+        };
+
+        // a single allocation fits 16383 nonces, the global memory two batches of 16382 nonces
+        assert_eq!(device.max_global_work_size(1), 16383);
+        assert_eq!(device.max_global_work_size(2), 16382);
+        assert_eq!(device.max_global_work_size(4), 8191);
+        assert!(device.validate(&device_config(8192, 256, 2)).is_ok());
+        assert!(device.validate(&device_config(8192, 512, 2)).is_err());
+        assert!(device.validate(&device_config(8000, 256, 2)).is_err());
+        assert!(device.validate(&device_config(8192, 256, 0)).is_err());
+        assert!(device.validate(&device_config(16384, 256, 2)).is_err());
+        assert!(device.validate(&device_config(4096, 256, 4)).is_ok());
+        assert!(device.validate(&device_config(8192, 256, 4)).is_err());
+
+        // larger devices are limited by the 32 bit offsets into the nonce buffer
+        let large = OclDeviceInfo { global_memory: 64 * 1024 * 1024 * 1024, max_allocation: 32 * 1024 * 1024 * 1024, ..device };
+        assert_eq!(large.max_global_work_size(1), 16383);
+        assert_eq!(large.max_global_work_size(2), 16383);
+        assert!(large.validate(&device_config(16384, 256, 1)).is_err());
+    }
+}
@@ -1,59 +1,280 @@
 extern crate ocl;
 extern crate num_iter;
 
-use ocl::{Platform, Device, Queue, Buffer, Kernel};
-use ocl::builders::{BufferBuilder, ContextBuilder, ProgramBuilder};
+use ocl::{Platform, Device, Queue, Buffer, Kernel, Event, Program};
+use ocl::builders::{BufferBuilder, ContextBuilder};
 use failure::Error;
+use std::collections::VecDeque;
+use std::mem;
+use std::path::Path;
+use std::time::Instant;
 
+use mine::MiningInfo;
 use util::config::{DeviceConfig};
 use util::constants::GEN_SIZE;
-use std::sync::mpsc::Receiver;
-use std::thread;
-use std::sync::mpsc::SyncSender;
-use plot::PlotResult;
+use plot::{DeadlineResult, NonceComputer, PlotFormat, PlotResult, nonces_per_minute};
+use plot::{ocl_device, ocl_program, ocl_tuning};
 
+/// Batches timed per work size when tuning
+const BENCHMARK_BATCHES: u64 = 3;
 
-pub struct OclNonceComputer {
-    device_config: DeviceConfig,
-    current_nonce: u64,
+/// Device buffers and kernels of one batch
+struct Slot {
     buffer: Buffer<u8>,
+    deadlines: Buffer<u64>,
     nonce_step_2: Kernel,
     nonce_step_3: Kernel,
+    nonce_step_4_poc2: Kernel,
+    calculate_deadlines: Kernel,
+}
+
+/// A batch that was enqueued but not returned yet
+struct Batch {
+    slot: usize,
+    start_nonce: u64,
+    /// Host memory the results are read into, only touched once `done` completed
+    nonces: Vec<u8>,
+    deadlines: Vec<u64>,
+    done: Event,
+}
+
+/// Computes nonces with OpenCL.
+///
+/// Every slot has its own device buffers, so while the results of one batch are transferred and
+/// consumed the next batches are already computed. Kernels run on one queue and results are read
+/// back on a second queue, chained by events. Every result handed out is immediately replaced by
+/// a new batch in the same slot.
+pub struct OclNonceComputer {
+    device_config: DeviceConfig,
+    device_name: String,
+    format: PlotFormat,
+    next_nonce: u64,
+    compute_queue: Queue,
+    transfer_queue: Queue,
+    generation_signature: Buffer<u8>,
+    slots: Vec<Slot>,
+    in_flight: VecDeque<Batch>,
+    /// Block of the batches in flight, `None` if they compute full nonces
+    mining_info: Option<MiningInfo>,
 }
 
 impl OclNonceComputer {
-    pub fn set_nonce(&mut self, nonce: u64) {
-        self.current_nonce = nonce;
+    pub fn new(device_config: DeviceConfig, address: u64, format: PlotFormat) -> Result<Self, Error> {
+        let (platform, device) = ocl_device::find_device(device_config.platform_id(), device_config.device_id())?;
+        let (platform_id, device_id) = (device_config.platform_id(), device_config.device_id());
+        Self::with_device(device_config, (platform_id, device_id), platform, device, address, format)
     }
 
-    pub fn current_nonce(&self) -> u64 {
-        self.current_nonce
+    /// One nonce computer for every device of every platform, all using the settings of `device_config`
+    pub fn all_devices(device_config: DeviceConfig, address: u64, format: PlotFormat) -> Result<Vec<Self>, Error> {
+        let mut nonce_computers = Vec::new();
+        for (platform_id, platform) in Platform::list().into_iter().enumerate() {
+            for (device_id, device) in Device::list_all(platform.clone())?.into_iter().enumerate() {
+                let ids = (platform_id as u32, device_id as u32);
+                nonce_computers.push(Self::with_device(device_config.clone(), ids, platform, device, address, format)?);
+            }
+        }
+        Ok(nonce_computers)
     }
 
-    pub fn new(device_config: DeviceConfig, address: u64) -> Result<Self, Error> {
-        // set up OpenCL kernels
-        let platform = Platform::list()[device_config.platform_id() as usize];
-        let device = Device::list_all(platform.clone())?[device_config.device_id() as usize];
+    /// Set up `device`, whose platform and device ids are `ids`
+    fn with_device(device_config: DeviceConfig, ids: (u32, u32), platform: Platform, device: Device, address: u64, format: PlotFormat)
+                   -> Result<Self, Error> {
+        let mut device_info = ocl_device::device_info(ids.0, ids.1, &platform, &device)?;
 
+        // set up OpenCL kernels
         let context = ContextBuilder::new()
             .platform(platform)
             .devices(device)
             .build()?;
 
-        let program = ProgramBuilder::new()
-            .src_file("kernel/shabal.cl")
-            .src_file("kernel/util.cl")
-            .src_file("kernel/nonce.cl")
-            .build(&context)?;
+        let program = ocl_program::build_program(&context, device, &device_info, device_config.kernel_dir(),
+                                                 Path::new(ocl_program::PROGRAM_CACHE_DIR))?;
+
+        // local work sizes are bounded by the kernels as well as by the device
+        let kernel_work_group_size = ocl_program::kernel_work_group_size(&program, device)?;
+        device_info.max_work_group_size = device_info.max_work_group_size.min(kernel_work_group_size);
+
+        let device_config = if device_config.auto_tune() {
+            let cache_path = Path::new(ocl_tuning::TUNING_CACHE_FILE);
+            ocl_tuning::tuned_config(&device_info, &device_config, cache_path,
+                                     |config| Self::benchmark(config.clone(), ids, platform, device, format))?
+        } else {
+            device_config
+        };
+        device_info.validate(&device_config)?;
+        let device_name = device_info.name.clone();
+
+        let compute_queue = Queue::new(&context, device, None)?;
+        let transfer_queue = Queue::new(&context, device, None)?;
+
+        let generation_signature: Buffer<u8> = BufferBuilder::new()
+            .queue(compute_queue.clone())
+            .len(32)
+            .build()?;
+
+        let slots = (0..device_config.buffers())
+            .map(|_| Slot::new(&device_config, &program, &compute_queue, &generation_signature, address))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            device_config,
+            device_name,
+            format,
+            next_nonce: 0u64,
+            compute_queue,
+            transfer_queue,
+            generation_signature,
+            slots,
+            in_flight: VecDeque::new(),
+            mining_info: None,
+        })
+    }
+
+    /// Speed of computing the deadlines of a few batches with `device_config` in nonces per minute
+    fn benchmark(device_config: DeviceConfig, ids: (u32, u32), platform: Platform, device: Device, format: PlotFormat)
+                 -> Result<f64, Error> {
+        let mut nonce_computer = Self::with_device(device_config, ids, platform, device, 0, format)?;
+        let mining_info = MiningInfo::new(vec![0u8; 32], 0, 1, Some(0));
+
+        // the first batch fills the pipeline and includes the kernel compilation by the driver
+        nonce_computer.compute_next_deadlines(&mining_info)?;
+        let start = Instant::now();
+        for _ in 0..BENCHMARK_BATCHES {
+            nonce_computer.compute_next_deadlines(&mining_info)?;
+        }
+        Ok(nonces_per_minute(BENCHMARK_BATCHES * nonce_computer.batch_size(), start.elapsed()))
+    }
+
+    /// Make sure the batches in flight compute what is requested next and that every slot is busy
+    fn prepare(&mut self, mining_info: Option<&MiningInfo>) -> Result<(), Error> {
+        if self.mining_info.as_ref() != mining_info {
+            self.discard_in_flight()?;
+
+            if let Some(mining_info) = mining_info {
+                // nothing is in flight, so the blocking write does not wait for other batches
+                self.generation_signature.write(mining_info.generation_signature()).enq()?;
+                for slot in &mut self.slots {
+                    slot.calculate_deadlines.set_arg_scl_named("p_scoop", mining_info.scoop_number() as u32)?;
+                    slot.calculate_deadlines.set_arg_scl_named("p_baseTarget", mining_info.base_target())?;
+                }
+            }
+            self.mining_info = mining_info.cloned();
+        }
+
+        while self.in_flight.len() < self.slots.len() {
+            let busy: Vec<usize> = self.in_flight.iter().map(|batch| batch.slot).collect();
+            let slot = (0..self.slots.len()).find(|slot| !busy.contains(slot)).expect("no free slot");
+            self.enqueue(slot)?;
+        }
+        Ok(())
+    }
+
+    /// Enqueue the next batch into a slot whose results were read back already
+    fn enqueue(&mut self, slot_index: usize) -> Result<(), Error> {
+        let global_work_size = self.device_config.global_work_size() as usize;
+        let slot = &mut self.slots[slot_index];
+        let mut computed = Event::empty();
+
+        // step 2
+        slot.nonce_step_2.set_arg_scl_named("p_startNonce", self.next_nonce)?;
+        unsafe { slot.nonce_step_2.enq()?; }
+
+        // step 3
+        if self.format == PlotFormat::PoC2 || self.mining_info.is_some() {
+            unsafe { slot.nonce_step_3.enq()?; }
+        } else {
+            unsafe { slot.nonce_step_3.cmd().enew(&mut computed).enq()?; }
+        }
+
+        // step 4
+        if self.format == PlotFormat::PoC2 {
+            if self.mining_info.is_some() {
+                unsafe { slot.nonce_step_4_poc2.enq()?; }
+            } else {
+                unsafe { slot.nonce_step_4_poc2.cmd().enew(&mut computed).enq()?; }
+            }
+        }
+
+        let mut batch = Batch {
+            slot: slot_index,
+            start_nonce: self.next_nonce,
+            nonces: Vec::new(),
+            deadlines: Vec::new(),
+            done: Event::empty(),
+        };
+
+        // get result without blocking, the host vectors are owned by the batch until `done` completed
+        if self.mining_info.is_some() {
+            unsafe { slot.calculate_deadlines.cmd().enew(&mut computed).enq()?; }
+            batch.deadlines = vec![0u64; global_work_size];
+            unsafe {
+                slot.deadlines.read(&mut batch.deadlines).queue(&self.transfer_queue).block(false)
+                    .ewait(&computed).enew(&mut batch.done).enq()?;
+            }
+        } else {
+            batch.nonces = vec![0u8; global_work_size * GEN_SIZE];
+            unsafe {
+                slot.buffer.read(&mut batch.nonces).queue(&self.transfer_queue).block(false).offset(0).len(global_work_size * GEN_SIZE)
+                    .dst_offset(0).ewait(&computed).enew(&mut batch.done).enq()?;
+            }
+        }
 
-        let queue = Queue::new(&context, device, None)?;
+        self.next_nonce += global_work_size as u64;
+        self.in_flight.push_back(batch);
+        Ok(())
+    }
+
+    /// Wait for the oldest batch and refill its slot right away
+    fn finish_oldest(&mut self) -> Result<Batch, Error> {
+        // a batch whose read failed stays in flight, so its memory is not freed while in use
+        self.in_flight.front().expect("no batch in flight").done.wait_for()?;
+        let batch = self.in_flight.pop_front().expect("no batch in flight");
+        self.enqueue(batch.slot)?;
+        Ok(batch)
+    }
 
+    /// Wait for all batches in flight and drop their results
+    fn discard_in_flight(&mut self) -> Result<(), Error> {
+        while let Some(batch) = self.in_flight.front() {
+            batch.done.wait_for()?;
+            let batch = self.in_flight.pop_front().expect("no batch in flight");
+            self.next_nonce = ::std::cmp::min(self.next_nonce, batch.start_nonce);
+        }
+        self.compute_queue.finish()?;
+        Ok(())
+    }
+}
+
+impl Drop for OclNonceComputer {
+    /// Pending reads write into the host memory of the batches in flight, so it is only freed once
+    /// they completed
+    fn drop(&mut self) {
+        if self.transfer_queue.finish().is_err() {
+            // the device may still write into the batches, leaking them is the only safe option
+            for batch in self.in_flight.drain(..) {
+                mem::forget(batch);
+            }
+            return;
+        }
+        self.discard_in_flight().ok();
+    }
+}
+
+impl Slot {
+    fn new(device_config: &DeviceConfig, program: &Program, queue: &Queue, generation_signature: &Buffer<u8>, address: u64)
+           -> Result<Self, Error> {
         let buffer: Buffer<u8> = BufferBuilder::new()
             .queue(queue.clone())
             .len(device_config.global_work_size() as usize * GEN_SIZE)
             .build()?;
 
-        let nonce_step_2 = Kernel::new("nonce_step2", &program)?
+        let deadlines: Buffer<u64> = BufferBuilder::new()
+            .queue(queue.clone())
+            .len(device_config.global_work_size() as usize)
+            .build()?;
+
+        let nonce_step_2 = Kernel::new("nonce_step2", program)?
             .queue(queue.clone())
             .gws(device_config.global_work_size())
             .lws(device_config.local_work_size())
@@ -62,47 +283,66 @@ impl OclNonceComputer {
             .arg_scl_named::<u64>("p_startNonce", None)
             .arg_scl_named::<u64>("p_address", Some(address));
 
-        let nonce_step_3 = Kernel::new("nonce_step3", &program)?
+        let nonce_step_3 = Kernel::new("nonce_step3", program)?
             .queue(queue.clone())
             .gws(device_config.global_work_size())
             .lws(device_config.local_work_size())
             .arg_buf(&buffer)
             .arg_scl_named("p_size", Some(device_config.global_work_size()));
 
-        Ok(Self { device_config, current_nonce: 0u64, buffer, nonce_step_2, nonce_step_3 })
-    }
+        let nonce_step_4_poc2 = Kernel::new("nonce_step4_poc2", program)?
+            .queue(queue.clone())
+            .gws(device_config.global_work_size())
+            .lws(device_config.local_work_size())
+            .arg_buf(&buffer)
+            .arg_scl_named("p_size", Some(device_config.global_work_size()));
 
-    /// Compute the next global_work_size many nonces
-    pub fn compute_next_nonces(&mut self) -> Result<PlotResult, Error> {
-        // step 2
-        self.nonce_step_2.set_arg_scl_named("p_startNonce", self.current_nonce)?;
-        unsafe { self.nonce_step_2.enq()?; }
+        let calculate_deadlines = Kernel::new("calculate_deadlines", program)?
+            .queue(queue.clone())
+            .gws(device_config.global_work_size())
+            .lws(device_config.local_work_size())
+            .arg_buf(&buffer)
+            .arg_scl_named("p_size", Some(device_config.global_work_size()))
+            .arg_buf(generation_signature)
+            .arg_scl_named::<u32>("p_scoop", None)
+            .arg_scl_named::<u64>("p_baseTarget", None)
+            .arg_buf(&deadlines);
 
-        // step 3
-        unsafe { self.nonce_step_3.enq()?; }
+        Ok(Self { buffer, deadlines, nonce_step_2, nonce_step_3, nonce_step_4_poc2, calculate_deadlines })
+    }
+}
 
-        // get result
-        let mut vec = vec![0u8; self.device_config.global_work_size() as usize * GEN_SIZE];
-        unsafe { self.buffer.read(&mut vec).block(true).offset(0).len(self.device_config.global_work_size() as usize * GEN_SIZE).dst_offset(0).enq()?; }
+impl NonceComputer for OclNonceComputer {
+    /// Batches already in flight are discarded, the next batch starts at `nonce`
+    fn set_nonce(&mut self, nonce: u64) {
+        self.discard_in_flight().expect("could not discard batches in flight");
+        self.next_nonce = nonce;
+    }
 
-        let start_nonce = self.current_nonce;
-        self.current_nonce += self.device_config.global_work_size() as u64;
+    fn current_nonce(&self) -> u64 {
+        self.in_flight.front().map(|batch| batch.start_nonce).unwrap_or(self.next_nonce)
+    }
 
-        return Ok(PlotResult::from_bytes(start_nonce, vec));
+    fn batch_size(&self) -> u64 {
+        self.device_config.global_work_size() as u64
     }
-}
 
-pub fn continuous_nonce_computer(mut ocl_nonce_computer: OclNonceComputer, receiver: Receiver<u64>, sender: SyncSender<PlotResult>) {
-    thread::spawn(move || {
-        loop {
-            if let Ok(nonce) = receiver.try_recv() {
-                ocl_nonce_computer.set_nonce(nonce);
-            }
+    fn device_name(&self) -> String {
+        self.device_name.clone()
+    }
 
-            let next_plot = ocl_nonce_computer.compute_next_nonces().expect("computing nonces failed");
-            sender.send(next_plot).expect("sending new nonces failed");
-        }
-    });
+    /// Compute the next global_work_size many nonces and read all of them back
+    fn compute_next_nonces(&mut self) -> Result<PlotResult, Error> {
+        self.prepare(None)?;
+        let batch = self.finish_oldest()?;
+        PlotResult::from_bytes(batch.start_nonce, batch.nonces, self.format)
+    }
 
+    /// Compute the next global_work_size many nonces and their deadlines on the device, reading
+    /// back only the deadlines
+    fn compute_next_deadlines(&mut self, mining_info: &MiningInfo) -> Result<DeadlineResult, Error> {
+        self.prepare(Some(mining_info))?;
+        let batch = self.finish_oldest()?;
+        Ok(DeadlineResult::new(batch.start_nonce, mining_info.height(), batch.deadlines))
+    }
 }
-
@@ -0,0 +1,176 @@
+use ocl::{Context, Device, Kernel, Program};
+use ocl::builders::ProgramBuilder;
+use ocl::enums::{KernelWorkGroupInfo, KernelWorkGroupInfoResult, ProgramInfo, ProgramInfoResult};
+use failure::{Error, err_msg};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use plot::ocl_device::OclDeviceInfo;
+
+/// Compiled programs, in the working directory next to `Settings.toml`
+pub const PROGRAM_CACHE_DIR: &str = "kernel-cache";
+
+/// Kernel sources compiled into the crate, in the order they are concatenated
+const KERNELS: [(&str, &str); 4] = [
+    ("shabal.cl", include_str!("../../kernel/shabal.cl")),
+    ("util.cl", include_str!("../../kernel/util.cl")),
+    ("nonce.cl", include_str!("../../kernel/nonce.cl")),
+    ("deadline.cl", include_str!("../../kernel/deadline.cl")),
+];
+
+/// Kernels launched by the OpenCL nonce computer
+const KERNEL_NAMES: [&str; 4] = ["nonce_step2", "nonce_step3", "nonce_step4_poc2", "calculate_deadlines"];
+
+/// The source of all kernels. A file with the name of a kernel in `kernel_dir` replaces the
+/// embedded kernel.
+pub fn kernel_source(kernel_dir: Option<&Path>) -> Result<String, Error> {
+    let mut source = String::new();
+    for &(file_name, embedded) in &KERNELS {
+        match kernel_dir.map(|kernel_dir| kernel_dir.join(file_name)) {
+            Some(ref path) if path.exists() => {
+                let kernel = fs::read_to_string(path)
+                    .map_err(|e| err_msg(format!("Could not read kernel {}: {}", path.display(), e)))?;
+                source.push_str(&kernel);
+            },
+            _ => source.push_str(embedded),
+        }
+        source.push('\n');
+    }
+    Ok(source)
+}
+
+/// Compile the kernels for `device`, reusing the program binary of an earlier start of the same
+/// kernels on the same device and driver from `cache_dir`. A cached binary the driver rejects is
+/// compiled again and replaced.
+pub fn build_program(context: &Context, device: Device, device_info: &OclDeviceInfo, kernel_dir: Option<&Path>,
+                     cache_dir: &Path) -> Result<Program, Error> {
+    let source = kernel_source(kernel_dir)?;
+    let cache_path = cache_dir.join(format!("{}.bin", cache_key(device_info, &source)));
+
+    if let Ok(binary) = fs::read(&cache_path) {
+        let cached = ProgramBuilder::new()
+            .binaries(&[&binary[..]])
+            .devices(device)
+            .build(context);
+        if let Ok(program) = cached {
+            return Ok(program);
+        }
+    }
+
+    let program = ProgramBuilder::new()
+        .src(source)
+        .devices(device)
+        .build(context)?;
+
+    // a program that can not be cached is still usable
+    if let Err(e) = cache_binary(&program, &cache_path) {
+        eprintln!("Could not cache the OpenCL program of {}: {}", device_info.name, e);
+    }
+    Ok(program)
+}
+
+/// Largest work group all kernels of `program` can be launched with on `device`. Kernels using
+/// many registers may not support the max work group size of the device.
+pub fn kernel_work_group_size(program: &Program, device: Device) -> Result<usize, Error> {
+    let mut work_group_size = usize::max_value();
+    for name in &KERNEL_NAMES {
+        match Kernel::new(*name, program)?.wg_info(device, KernelWorkGroupInfo::WorkGroupSize) {
+            KernelWorkGroupInfoResult::WorkGroupSize(size) => work_group_size = work_group_size.min(size),
+            KernelWorkGroupInfoResult::Error(e) =>
+                return Err(err_msg(format!("Could not query the work group size of kernel {}: {}", name, e))),
+            other => return Err(err_msg(format!("Unexpected kernel work group info {:?}", other))),
+        }
+    }
+    Ok(work_group_size)
+}
+
+/// File name of the cached binary, changes with the kernel sources, the device and its driver.
+///
+/// `DefaultHasher` may change between Rust releases, which only costs one compile.
+fn cache_key(device_info: &OclDeviceInfo, source: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    (&device_info.platform_name, &device_info.name, &device_info.vendor, &device_info.version, &device_info.driver_version)
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Write the binary of the single device of `program` through a temporary file
+fn cache_binary(program: &Program, path: &Path) -> Result<(), Error> {
+    let binary = match program.info(ProgramInfo::Binaries) {
+        ProgramInfoResult::Binaries(mut binaries) => binaries.pop()
+            .filter(|binary| !binary.is_empty())
+            .ok_or_else(|| err_msg("the driver returned no binary"))?,
+        ProgramInfoResult::Error(e) => return Err(err_msg(format!("{}", e))),
+        other => return Err(err_msg(format!("Unexpected program info {:?}", other))),
+    };
+
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, &binary)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn device(driver_version: &str) -> OclDeviceInfo {
+        OclDeviceInfo {
+            platform_id: 0,
+            device_id: 0,
+            platform_name: String::from("platform"),
+            name: String::from("device"),
+            vendor: String::from("vendor"),
+            version: String::from("OpenCL 1.2"),
+            driver_version: driver_version.to_string(),
+            compute_units: 4,
+            global_memory: 1 << 30,
+            local_memory: 48 * 1024,
+            max_allocation: 1 << 28,
+            max_work_group_size: 256,
+        }
+    }
+
+    #[test]
+    fn embeds_kernels() {
+        let source = kernel_source(None).unwrap();
+        for kernel in &["nonce_step2", "nonce_step3", "nonce_step4_poc2", "calculate_deadlines"] {
+            assert!(source.contains(&format!("__kernel void {}", kernel)), "{} is missing", kernel);
+        }
+        // a missing override directory keeps the embedded kernels
+        assert_eq!(kernel_source(Some(Path::new("/nonexistent/kernel"))).unwrap(), source);
+    }
+
+    #[test]
+    fn overrides_kernels_from_directory() {
+        let directory = env::temp_dir().join(format!("burst-rust-kernel-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("deadline.cl"), "// experimental deadline kernel").unwrap();
+
+        let source = kernel_source(Some(&directory)).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(source.starts_with(KERNELS[0].1));
+        assert!(source.contains("nonce_step2"));
+        assert!(source.ends_with("// experimental deadline kernel\n"));
+        assert!(!source.contains("calculate_deadlines"));
+    }
+
+    #[test]
+    fn cache_key_depends_on_driver_and_source() {
+        let source = kernel_source(None).unwrap();
+        let key = cache_key(&device("390.48"), &source);
+
+        assert_eq!(key.len(), 16);
+        assert_eq!(cache_key(&device("390.48"), &source), key);
+        assert_ne!(cache_key(&device("396.24"), &source), key);
+        assert_ne!(cache_key(&device("390.48"), "// other kernels"), key);
+    }
+}
@@ -0,0 +1,249 @@
+use failure::{Error, err_msg};
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use plot::ocl_device::OclDeviceInfo;
+use util::config::DeviceConfig;
+
+/// Work sizes found by auto tuning, cached in the working directory next to `Settings.toml`
+pub const TUNING_CACHE_FILE: &str = "tuning-cache.json";
+
+/// Smallest local work size that is benchmarked
+const MIN_LOCAL_WORK_SIZE: u32 = 32;
+/// Work groups per compute unit of the global work size used to compare local work sizes
+const LOCAL_TUNING_GROUPS: u32 = 4;
+/// A larger global work size has to be at least this much faster to be preferred
+const MIN_IMPROVEMENT: f64 = 1.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorkSizes {
+    pub global_work_size: u32,
+    pub local_work_size: u32,
+    /// Benchmarked speed of these work sizes
+    pub nonces_per_minute: f64,
+}
+
+/// Tuned work sizes by device name
+#[derive(Debug, Clone)]
+pub struct TuningCache {
+    path: PathBuf,
+    devices: BTreeMap<String, WorkSizes>,
+}
+
+impl TuningCache {
+    /// Load the cache, a missing file is an empty cache
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let devices = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)
+                .map_err(|e| err_msg(format!("Could not read tuning cache {}: {}", path.display(), e)))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, devices })
+    }
+
+    pub fn get(&self, device_name: &str) -> Option<WorkSizes> {
+        self.devices.get(device_name).cloned()
+    }
+
+    pub fn insert(&mut self, device_name: &str, work_sizes: WorkSizes) {
+        self.devices.insert(device_name.to_string(), work_sizes);
+    }
+
+    /// Write the cache through a temporary file, so an interrupted write keeps the old cache
+    pub fn save(&self) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&self.devices)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// `device_config` with the cached work sizes of the device, benchmarking the device first if
+/// there are none or they do not fit the config anymore, e.g. after changing `buffers`
+pub fn tuned_config<F>(device: &OclDeviceInfo, device_config: &DeviceConfig, cache_path: &Path, benchmark: F)
+                       -> Result<DeviceConfig, Error>
+    where F: FnMut(&DeviceConfig) -> Result<f64, Error> {
+    let mut cache = TuningCache::open(cache_path)?;
+    if let Some(work_sizes) = cache.get(&device.name) {
+        let cached_config = device_config.with_work_sizes(work_sizes.global_work_size, work_sizes.local_work_size);
+        if device.validate(&cached_config).is_ok() {
+            return Ok(cached_config);
+        }
+    }
+
+    eprintln!("Tuning work sizes of {}", device.name);
+    let work_sizes = tune(device, device_config, benchmark)?;
+    eprintln!("Using global_work_size = {}, local_work_size = {} on {}: {:.0} Nonces/min",
+              work_sizes.global_work_size, work_sizes.local_work_size, device.name, work_sizes.nonces_per_minute);
+    cache.insert(&device.name, work_sizes);
+    cache.save()?;
+    Ok(device_config.with_work_sizes(work_sizes.global_work_size, work_sizes.local_work_size))
+}
+
+/// Find the fastest work sizes, `benchmark` returns the speed of a config in nonces per minute.
+///
+/// Local work sizes are compared at a global work size of a few work groups per compute unit.
+/// With the fastest one the global work size is doubled until the speed stops improving or the
+/// batches no longer fit into the memory of the device. Work sizes the benchmark fails for are
+/// skipped, tuning only fails if no work sizes could be benchmarked at all.
+pub fn tune<F>(device: &OclDeviceInfo, device_config: &DeviceConfig, mut benchmark: F) -> Result<WorkSizes, Error>
+    where F: FnMut(&DeviceConfig) -> Result<f64, Error> {
+    let max_global_work_size = device.max_global_work_size(device_config.buffers());
+
+    let mut best: Option<WorkSizes> = None;
+    let mut last_error = None;
+    for local_work_size in local_work_sizes(device) {
+        let global_work_size = local_work_size as u64 * device.compute_units.max(1) as u64 * LOCAL_TUNING_GROUPS as u64;
+        let global_work_size = largest_multiple(global_work_size.min(max_global_work_size), local_work_size);
+        if global_work_size == 0 {
+            continue;
+        }
+        let work_sizes = match measure(device_config, global_work_size, local_work_size, &mut benchmark) {
+            Ok(work_sizes) => work_sizes,
+            Err(e) => {
+                eprintln!("Skipping local_work_size = {} on {}: {}", local_work_size, device.name, e);
+                last_error = Some(e);
+                continue;
+            },
+        };
+        if best.map(|best| work_sizes.nonces_per_minute > best.nonces_per_minute).unwrap_or(true) {
+            best = Some(work_sizes);
+        }
+    }
+    let mut best = best.ok_or_else(|| match last_error {
+        Some(e) => err_msg(format!("Could not benchmark any work sizes on {}: {}", device.name, e)),
+        None => err_msg(format!("{} does not have enough memory for a single work group", device.name)),
+    })?;
+
+    let mut global_work_size = best.global_work_size as u64 * 2;
+    while global_work_size <= max_global_work_size {
+        let work_sizes = match measure(device_config, global_work_size as u32, best.local_work_size, &mut benchmark) {
+            Ok(work_sizes) => work_sizes,
+            Err(e) => {
+                eprintln!("Skipping global_work_size = {} on {}: {}", global_work_size, device.name, e);
+                break;
+            },
+        };
+        if work_sizes.nonces_per_minute < best.nonces_per_minute * MIN_IMPROVEMENT {
+            break;
+        }
+        best = work_sizes;
+        global_work_size *= 2;
+    }
+    Ok(best)
+}
+
+fn measure<F>(device_config: &DeviceConfig, global_work_size: u32, local_work_size: u32, benchmark: &mut F) -> Result<WorkSizes, Error>
+    where F: FnMut(&DeviceConfig) -> Result<f64, Error> {
+    let nonces_per_minute = benchmark(&device_config.with_work_sizes(global_work_size, local_work_size))?;
+    Ok(WorkSizes { global_work_size, local_work_size, nonces_per_minute })
+}
+
+/// Powers of two up to the max work group size of the device, which `OclNonceComputer` bounds by
+/// the work group size of its kernels
+fn local_work_sizes(device: &OclDeviceInfo) -> Vec<u32> {
+    let max_work_group_size = device.max_work_group_size as u32;
+    let mut local_work_sizes: Vec<u32> = (0..32).map(|shift| 1u32 << shift)
+        .skip_while(|&size| size < MIN_LOCAL_WORK_SIZE.min(max_work_group_size))
+        .take_while(|&size| size <= max_work_group_size)
+        .collect();
+    if local_work_sizes.is_empty() {
+        local_work_sizes.push(max_work_group_size.max(1));
+    }
+    local_work_sizes
+}
+
+fn largest_multiple(value: u64, factor: u32) -> u32 {
+    (value / factor as u64 * factor as u64) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn device(max_work_group_size: usize, global_memory: u64) -> OclDeviceInfo {
+        OclDeviceInfo {
+            platform_id: 0,
+            device_id: 0,
+            platform_name: String::from("platform"),
+            name: String::from("device"),
+            vendor: String::from("vendor"),
+            version: String::from("OpenCL 1.2"),
+            driver_version: String::from("390.48"),
+            compute_units: 4,
+            global_memory,
+            local_memory: 48 * 1024,
+            max_allocation: global_memory,
+            max_work_group_size,
+        }
+    }
+
+    fn device_config() -> DeviceConfig {
+        serde_json::from_str(r#"{"auto_tune": true, "buffers": 2}"#).unwrap()
+    }
+
+    /// Fastest at a local work size of 64, the speed stops growing beyond 2048 nonces per batch
+    fn fake_benchmark(config: &DeviceConfig) -> Result<f64, Error> {
+        let local_factor = if config.local_work_size() == 64 { 2.0 } else { 1.0 };
+        Ok(local_factor * config.global_work_size().min(2048) as f64)
+    }
+
+    #[test]
+    fn picks_fastest_work_sizes() {
+        let mut benchmarked = Vec::new();
+        let work_sizes = tune(&device(256, 1 << 34), &device_config(), |config| {
+            benchmarked.push((config.global_work_size(), config.local_work_size()));
+            fake_benchmark(config)
+        }).unwrap();
+
+        assert_eq!((work_sizes.global_work_size, work_sizes.local_work_size), (2048, 64));
+        assert_eq!(benchmarked, vec![(512, 32), (1024, 64), (2048, 128), (4096, 256), (2048, 64), (4096, 64)]);
+    }
+
+    #[test]
+    fn keeps_work_sizes_within_memory() {
+        // two batches of 1000 nonces fit into the memory
+        let device = device(64, 1000 * 2 * (::util::constants::GEN_SIZE as u64 + 8));
+        let work_sizes = tune(&device, &device_config(), fake_benchmark).unwrap();
+        assert_eq!((work_sizes.global_work_size, work_sizes.local_work_size), (960, 64));
+        assert_eq!(local_work_sizes(&self::device(16, 1 << 30)), vec![16]);
+    }
+
+    #[test]
+    fn skips_failing_work_sizes() {
+        // local work sizes above 64 and batches above 1024 nonces fail, e.g. for lack of registers or memory
+        let work_sizes = tune(&device(256, 1 << 34), &device_config(), |config| {
+            if config.local_work_size() > 64 || config.global_work_size() > 1024 {
+                return Err(err_msg("CL_OUT_OF_RESOURCES"));
+            }
+            fake_benchmark(config)
+        }).unwrap();
+        assert_eq!((work_sizes.global_work_size, work_sizes.local_work_size), (1024, 64));
+
+        let error = tune(&device(256, 1 << 34), &device_config(), |_| Err(err_msg("CL_OUT_OF_RESOURCES"))).unwrap_err();
+        assert!(error.to_string().contains("CL_OUT_OF_RESOURCES"));
+    }
+
+    #[test]
+    fn caches_tuned_work_sizes() {
+        let directory = env::temp_dir().join(format!("burst-rust-tuning-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let cache_path = directory.join(TUNING_CACHE_FILE);
+        let device = device(256, 1 << 34);
+
+        let tuned = tuned_config(&device, &device_config(), &cache_path, fake_benchmark).unwrap();
+        let cached = tuned_config(&device, &device_config(), &cache_path, |_| Err(err_msg("cached work sizes expected"))).unwrap();
+        let cache = TuningCache::open(&cache_path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!((tuned.global_work_size(), tuned.local_work_size(), tuned.auto_tune()), (2048, 64, false));
+        assert_eq!((cached.global_work_size(), cached.local_work_size()), (2048, 64));
+        assert_eq!(cache.get("device"), Some(WorkSizes { global_work_size: 2048, local_work_size: 64, nonces_per_minute: 4096.0 }));
+        assert_eq!(cache.get("other device"), None);
+    }
+}
@@ -0,0 +1,447 @@
+use byteorder::{ByteOrder, LittleEndian};
+use failure::{Error, err_msg};
+use std::cmp::{max, min};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use plot::file::{PlotFile, PlotFileInfo};
+use plot::progress::{sidecar_path, load_checkpoint, save_checkpoint};
+use plot::verify::spot_check;
+use plot::writer::{file_offset, preallocate};
+use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE};
+
+/// Rewrite a staggered plot file into the optimized layout without needing space for a second copy.
+///
+/// The file is transposed block by block, where a block holds one scoop of a stagger group. Every
+/// batch of moved blocks is journaled next to the file first, so an interrupted run continues where
+/// it stopped when called again. At most `memory_bytes` are buffered, but never less than one block.
+/// Returns the path of the optimized file.
+pub fn optimize_in_place<P: AsRef<Path>>(path: P, memory_bytes: usize) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    let info = {
+        let file_name = path.file_name().and_then(|name| name.to_str())
+            .ok_or_else(|| err_msg(format!("{} is not a plot file", path.display())))?;
+        PlotFileInfo::parse(file_name)?
+    };
+    let destination = path.with_file_name(info.optimized().file_name());
+    let journal_path = sidecar_path(&destination, "optimizing");
+
+    // interrupted after renaming the finished file, called with either of its names
+    if journal_path.exists() && destination.exists() && (info.is_optimized() || !path.exists()) {
+        fs::remove_file(&journal_path)?;
+        return Ok(destination);
+    }
+    if info.is_optimized() {
+        return Err(err_msg(format!("{} is already optimized", path.display())));
+    }
+    let info = *PlotFile::open(path)?.info();
+
+    let block_size = info.stagger() as usize * SCOOP_SIZE;
+    let mut transposition = Transposition {
+        file: OpenOptions::new().read(true).write(true).open(path)?,
+        groups: info.nonces() / info.stagger(),
+        block_size,
+        batch_blocks: max(1, memory_bytes / block_size),
+        journal_path: journal_path.clone(),
+    };
+    transposition.run()?;
+
+    // the journal stays until the checked file has its final name
+    spot_check(&mut PlotFile::open_as(path, info.optimized())?)?;
+    fs::rename(path, &destination)?;
+    fs::remove_file(&journal_path)?;
+    Ok(destination)
+}
+
+/// Write an optimized copy of a staggered plot file to `directory`, leaving the original untouched.
+///
+/// The copy is written scoop by scoop, reading at most `memory_bytes` at once. Progress is
+/// checkpointed after every scoop, so an interrupted run continues where it stopped when called
+/// again. Returns the path of the optimized file.
+pub fn optimize_to<P: AsRef<Path>, Q: AsRef<Path>>(path: P, directory: Q, memory_bytes: usize) -> Result<PathBuf, Error> {
+    let mut source = PlotFile::open(path.as_ref())?;
+    let info = *source.info();
+    if info.is_optimized() {
+        return Err(err_msg(format!("{} is already optimized", path.as_ref().display())));
+    }
+
+    let destination = directory.as_ref().join(info.optimized().file_name());
+    let partial_path = sidecar_path(&destination, "optimizing");
+    let progress_path = sidecar_path(&destination, "optimize-progress");
+
+    // interrupted after renaming the finished copy
+    if destination.exists() && progress_path.exists() && !partial_path.exists() {
+        fs::remove_file(&progress_path)?;
+        return Ok(destination);
+    }
+
+    let mut output = OpenOptions::new().write(true).create(true).open(&partial_path)?;
+    let first_scoop = match load_checkpoint(&progress_path)? {
+        Some(scoop_number) => scoop_number as u16,
+        None => {
+            preallocate(&output, info.file_size())?;
+            0
+        },
+    };
+
+    let nonces = info.nonces();
+    let chunk_nonces = max(1, memory_bytes / SCOOP_SIZE) as u64;
+    for scoop_number in first_scoop..SCOOPS_PER_PLOT {
+        output.seek(SeekFrom::Start(file_offset(0, scoop_number, nonces)))?;
+
+        let mut nonce_index = 0;
+        while nonce_index < nonces {
+            let chunk = min(chunk_nonces, nonces - nonce_index);
+            output.write_all(source.read_scoop_range(scoop_number, nonce_index, chunk)?.bytes())?;
+            nonce_index += chunk;
+        }
+
+        output.sync_data()?;
+        save_checkpoint(&progress_path, scoop_number as u64 + 1)?;
+    }
+    output.sync_all()?;
+
+    spot_check(&mut PlotFile::open_as(&partial_path, info.optimized())?)?;
+    fs::rename(&partial_path, &destination)?;
+    fs::remove_file(&progress_path)?;
+    Ok(destination)
+}
+
+/// In place transposition of the blocks of a staggered file.
+///
+/// Block `(group, scoop)` is stored at index `group * 4096 + scoop` and moves to
+/// `scoop * groups + group`. The permutation is applied one cycle at a time, starting each cycle
+/// at its smallest index. Moves are collected in batches that may span several short cycles.
+struct Transposition {
+    file: File,
+    groups: u64,
+    block_size: usize,
+    batch_blocks: usize,
+    journal_path: PathBuf,
+}
+
+impl Transposition {
+    fn blocks(&self) -> u64 {
+        self.groups * SCOOPS_PER_PLOT as u64
+    }
+
+    fn destination(&self, index: u64) -> u64 {
+        (index % SCOOPS_PER_PLOT as u64) * self.groups + index / SCOOPS_PER_PLOT as u64
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let mut visited = vec![0u64; (self.blocks() as usize + 63) / 64];
+        let mut batch = Vec::new();
+        let mut first_leader = 0;
+
+        if let Some(journal) = Journal::load(&self.journal_path, self.block_size)? {
+            // every index below the journaled leader belongs to a finished cycle
+            for index in 0..journal.leader + 1 {
+                self.mark_cycle(&mut visited, index);
+            }
+            self.apply(&journal.writes)?;
+            if let Some((position, carried)) = journal.carried {
+                self.follow_cycle(journal.leader, position, carried, &mut batch)?;
+            }
+            first_leader = journal.leader + 1;
+        }
+
+        for leader in first_leader..self.blocks() {
+            if is_visited(&visited, leader) {
+                continue;
+            }
+            self.mark_cycle(&mut visited, leader);
+            if self.destination(leader) == leader {
+                continue;
+            }
+
+            let carried = self.read_block(leader)?;
+            let position = self.destination(leader);
+            self.follow_cycle(leader, position, carried, &mut batch)?;
+        }
+
+        // the last index is a fixed point, journaling it as leader marks every cycle as finished,
+        // even if the last batch was already written
+        let last = self.blocks() - 1;
+        self.flush(&mut batch, last, None)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Move `carried` to `position` and follow the cycle until it returns to `leader`
+    fn follow_cycle(&mut self, leader: u64, mut position: u64, mut carried: Vec<u8>,
+                    batch: &mut Vec<(u64, Vec<u8>)>) -> Result<(), Error> {
+        loop {
+            // the old block of the leader was taken when the cycle started
+            if position == leader {
+                batch.push((position, carried));
+                break;
+            }
+
+            let old = self.read_block(position)?;
+            batch.push((position, carried));
+            carried = old;
+            position = self.destination(position);
+
+            if batch.len() >= self.batch_blocks {
+                self.flush(batch, leader, Some((position, &carried)))?;
+            }
+        }
+
+        if batch.len() >= self.batch_blocks {
+            self.flush(batch, leader, None)?;
+        }
+        Ok(())
+    }
+
+    /// Journal and write a batch of moves. `carried` is the next move of an unfinished cycle.
+    fn flush(&mut self, batch: &mut Vec<(u64, Vec<u8>)>, leader: u64, carried: Option<(u64, &[u8])>) -> Result<(), Error> {
+        Journal::save(&self.journal_path, leader, batch, carried)?;
+        self.apply(batch)?;
+        batch.clear();
+        Ok(())
+    }
+
+    fn apply(&mut self, writes: &[(u64, Vec<u8>)]) -> Result<(), Error> {
+        for &(position, ref block) in writes {
+            self.file.seek(SeekFrom::Start(position * self.block_size as u64))?;
+            self.file.write_all(block)?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn read_block(&mut self, index: u64) -> Result<Vec<u8>, Error> {
+        let mut block = vec![0u8; self.block_size];
+        self.file.seek(SeekFrom::Start(index * self.block_size as u64))?;
+        self.file.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    fn mark_cycle(&self, visited: &mut [u64], leader: u64) {
+        let mut index = leader;
+        while !is_visited(visited, index) {
+            visited[index as usize / 64] |= 1 << (index % 64);
+            index = self.destination(index);
+        }
+    }
+}
+
+fn is_visited(visited: &[u64], index: u64) -> bool {
+    visited[index as usize / 64] & (1 << (index % 64)) != 0
+}
+
+/// Blocks about to be written, and the next move of the cycle of `leader` if it is unfinished
+struct Journal {
+    leader: u64,
+    writes: Vec<(u64, Vec<u8>)>,
+    carried: Option<(u64, Vec<u8>)>,
+}
+
+impl Journal {
+    const HEADER_SIZE: usize = 24;
+
+    fn load(path: &Path, block_size: usize) -> Result<Option<Self>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        if data.len() < Self::HEADER_SIZE {
+            return Err(err_msg(format!("Journal {} is corrupt", path.display())));
+        }
+
+        let leader = LittleEndian::read_u64(&data[0..8]);
+        let num_writes = LittleEndian::read_u64(&data[8..16]) as usize;
+        let has_carried = LittleEndian::read_u64(&data[16..24]) != 0;
+        let entry_size = 8 + block_size;
+        if data.len() != Self::HEADER_SIZE + (num_writes + has_carried as usize) * entry_size {
+            return Err(err_msg(format!("Journal {} is corrupt", path.display())));
+        }
+
+        let mut writes: Vec<(u64, Vec<u8>)> = data[Self::HEADER_SIZE..].chunks(entry_size)
+            .map(|entry| (LittleEndian::read_u64(&entry[..8]), entry[8..].to_vec()))
+            .collect();
+        let carried = if has_carried { writes.pop() } else { None };
+        Ok(Some(Self { leader, writes, carried }))
+    }
+
+    /// Replace the journal atomically, so a crash leaves either the old or the new one
+    fn save(path: &Path, leader: u64, writes: &[(u64, Vec<u8>)], carried: Option<(u64, &[u8])>) -> Result<(), Error> {
+        let mut header = [0u8; Self::HEADER_SIZE];
+        LittleEndian::write_u64(&mut header[0..8], leader);
+        LittleEndian::write_u64(&mut header[8..16], writes.len() as u64);
+        LittleEndian::write_u64(&mut header[16..24], carried.is_some() as u64);
+
+        let temporary_path = sidecar_path(path, "tmp");
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&header)?;
+        for (position, block) in writes.iter().map(|&(position, ref block)| (position, &block[..])).chain(carried) {
+            let mut position_bytes = [0u8; 8];
+            LittleEndian::write_u64(&mut position_bytes, position);
+            file.write_all(&position_bytes)?;
+            file.write_all(block)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use plot::{PlotFormat, PlotResult, scoop_offset};
+    use plot::cpu_nonce_computer::generate_nonce;
+    use plot::writer::{PlotWriter, incomplete_plot_files};
+    use util::constants::GEN_SIZE;
+
+    fn staggered_plot(directory: &Path, nonces: u64, stagger: u64) -> PathBuf {
+        fs::create_dir_all(directory).unwrap();
+        let mut data = vec![0u8; nonces as usize * GEN_SIZE];
+        for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+            generate_nonce(9, 100 + index as u64, PlotFormat::PoC1, nonce);
+        }
+        let mut writer = PlotWriter::create(directory, 9, 100, nonces, Some(stagger), PlotFormat::PoC1, nonces).unwrap();
+        writer.write(&PlotResult::from_bytes(100, data, PlotFormat::PoC1).unwrap()).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn assert_optimized(path: &Path, nonces: u64) {
+        assert_eq!(path.file_name().unwrap().to_str().unwrap(), format!("9_100_{}_{}", nonces, nonces));
+        let mut plot_file = PlotFile::open(path).unwrap();
+        let scoops: Vec<_> = (0..SCOOPS_PER_PLOT).step_by(511).map(|scoop_number| plot_file.read_scoops(scoop_number).unwrap()).collect();
+
+        let mut buffer = vec![0u8; GEN_SIZE];
+        for nonce_index in 0..nonces {
+            generate_nonce(9, 100 + nonce_index, PlotFormat::PoC1, &mut buffer);
+            for (scoop_number, scoops) in (0..SCOOPS_PER_PLOT).step_by(511).zip(&scoops) {
+                let offset = scoop_offset(scoop_number).unwrap();
+                assert_eq!(&scoops.scoop_data(nonce_index).unwrap().bytes()[..], &buffer[offset..offset + SCOOP_SIZE]);
+            }
+        }
+    }
+
+    #[test]
+    fn optimizes_in_place_with_small_batches() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 6, 2);
+
+        let optimized = optimize_in_place(&path, 7 * 2 * SCOOP_SIZE).unwrap();
+        assert_optimized(&optimized, 6);
+        assert!(!path.exists());
+        assert!(!sidecar_path(&optimized, "optimizing").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn optimizes_in_place_with_large_batches() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-batches-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 8, 1);
+
+        let optimized = optimize_in_place(&path, 1 << 20).unwrap();
+        assert_optimized(&optimized, 8);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn optimizes_to_new_file() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-copy-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 4, 2);
+        let target = directory.join("optimized");
+        fs::create_dir_all(&target).unwrap();
+
+        let optimized = optimize_to(&path, &target, 3 * SCOOP_SIZE).unwrap();
+        assert_optimized(&optimized, 4);
+        assert!(path.exists());
+        assert!(!sidecar_path(&optimized, "optimize-progress").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-resume-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 4, 2);
+        let destination = directory.join("9_100_4_4");
+
+        // pretend an earlier run wrote the first 10 scoops and nothing after them
+        let partial_path = sidecar_path(&destination, "optimizing");
+        optimize_to(&path, &directory, 1 << 20).unwrap();
+        fs::rename(&destination, &partial_path).unwrap();
+        save_checkpoint(&sidecar_path(&destination, "optimize-progress"), 10).unwrap();
+        let mut partial = OpenOptions::new().write(true).open(&partial_path).unwrap();
+        let unfinished = file_offset(0, 10, 4);
+        partial.seek(SeekFrom::Start(unfinished)).unwrap();
+        partial.write_all(&vec![0xff; (4 * ::util::constants::PLOT_SIZE as u64 - unfinished) as usize]).unwrap();
+
+        let optimized = optimize_to(&path, &directory, 1 << 20).unwrap();
+        assert_optimized(&optimized, 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn finishes_copy_renamed_before_checkpoint_was_removed() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-renamed-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 4, 2);
+        let target = directory.join("optimized");
+        fs::create_dir_all(&target).unwrap();
+
+        // the finished copy has its final name, the checkpoint of the last scoop is still there
+        let destination = optimize_to(&path, &target, 1 << 20).unwrap();
+        save_checkpoint(&sidecar_path(&destination, "optimize-progress"), SCOOPS_PER_PLOT as u64).unwrap();
+        // the checkpoint is not mistaken for the progress of an unfinished plot
+        assert!(incomplete_plot_files(&target).unwrap().is_empty());
+        assert!(PlotFile::open(&destination).is_ok());
+
+        let optimized = optimize_to(&path, &target, 1 << 20).unwrap();
+        assert_eq!(optimized, destination);
+        assert!(!sidecar_path(&optimized, "optimize-progress").exists());
+        assert!(!sidecar_path(&optimized, "optimizing").exists());
+        assert_optimized(&optimized, 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn finishes_transposition_renamed_before_journal_was_removed() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-renamed-in-place-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 4, 2);
+
+        let destination = optimize_in_place(&path, 1 << 20).unwrap();
+        let journal_path = sidecar_path(&destination, "optimizing");
+        fs::write(&journal_path, b"").unwrap();
+
+        // a rerun with the original name only removes the journal
+        let optimized = optimize_in_place(&path, 1 << 20).unwrap();
+        assert_eq!(optimized, destination);
+        assert!(!journal_path.exists());
+        assert_optimized(&optimized, 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn resumes_interrupted_transposition() {
+        let directory = env::temp_dir().join(format!("burst-rust-optimize-journal-{}", ::std::process::id()));
+        let path = staggered_plot(&directory, 4, 2);
+        let journal_path = directory.join("9_100_4_4.optimizing");
+
+        // block 1 (group 0, scoop 1) moves to index 2 in the first cycle; journal that move
+        // with the old block of index 2 carried on, as if the process died after saving it
+        let mut transposition = Transposition {
+            file: OpenOptions::new().read(true).write(true).open(&path).unwrap(),
+            groups: 2,
+            block_size: 2 * SCOOP_SIZE,
+            batch_blocks: 1,
+            journal_path: journal_path.clone(),
+        };
+        let carried = transposition.read_block(1).unwrap();
+        let old = transposition.read_block(2).unwrap();
+        Journal::save(&journal_path, 1, &[(2, carried)], Some((4, &old))).unwrap();
+
+        let optimized = optimize_in_place(&path, 1 << 20).unwrap();
+        assert_optimized(&optimized, 4);
+        assert!(!journal_path.exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use byteorder::{ByteOrder, LittleEndian};
+use failure::{Error, err_msg};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Path of a file next to `path` with `extension` appended to its name
+pub fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
+}
+
+/// Read a counter written by `save_checkpoint`, `None` if there is none
+pub fn load_checkpoint(path: &Path) -> Result<Option<u64>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if data.len() != 8 {
+        return Err(err_msg(format!("Checkpoint {} is corrupt", path.display())));
+    }
+    Ok(Some(LittleEndian::read_u64(&data)))
+}
+
+pub fn save_checkpoint(path: &Path, value: u64) -> Result<(), Error> {
+    let mut data = [0u8; 8];
+    LittleEndian::write_u64(&mut data, value);
+    write_atomically(path, &data)
+}
+
+/// Replace the content of `path` so a crash leaves either the old or the new content
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let temporary_path = sidecar_path(path, "tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn saves_and_loads_checkpoints() {
+        let directory = env::temp_dir().join(format!("burst-rust-progress-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = sidecar_path(&directory.join("1_0_10"), "progress");
+
+        let missing = load_checkpoint(&path).unwrap();
+        save_checkpoint(&path, 7).unwrap();
+        let saved = load_checkpoint(&path).unwrap();
+        fs::write(&path, b"short").unwrap();
+        let corrupt = load_checkpoint(&path);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(path.file_name().unwrap(), "1_0_10.progress");
+        assert_eq!((missing, saved), (None, Some(7)));
+        assert!(corrupt.is_err());
+    }
+}
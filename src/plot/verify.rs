@@ -0,0 +1,215 @@
+use failure::{Error, err_msg};
+use rand;
+use rand::seq::sample_indices;
+use rayon;
+use rayon::prelude::*;
+use std::cmp::min;
+
+use plot::cpu_nonce_computer::generate_nonce;
+use plot::file::PlotFile;
+use plot::scoop_offset;
+use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE, GEN_SIZE};
+
+/// Nonces checked by `spot_check`
+const SPOT_CHECK_SAMPLES: u64 = 16;
+/// Scoops of every nonce checked by `spot_check`
+const SPOT_CHECK_SCOOPS: usize = 8;
+
+/// Which scoops of a sampled nonce are compared with the regenerated nonce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoopSelection {
+    All,
+    Random(usize),
+}
+
+/// Consecutive sampled nonces that did not match their regenerated nonce
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptedRange {
+    /// First and last corrupted nonce that was sampled
+    pub first_nonce: u64,
+    pub last_nonce: u64,
+    /// Nonces between the closest intact samples, the corruption can not extend beyond them
+    pub possible_first_nonce: u64,
+    pub possible_last_nonce: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub checked_nonces: u64,
+    pub checked_scoops: u64,
+    pub corrupted: Vec<CorruptedRange>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.corrupted.is_empty()
+    }
+}
+
+/// Regenerate `samples` random nonces of a plot file and compare the selected scoops byte for byte.
+///
+/// The first and last nonce of the file are always part of the sample.
+pub fn verify_plot_file(plot_file: &mut PlotFile, samples: u64, scoops: ScoopSelection) -> Result<VerifyReport, Error> {
+    if scoops == ScoopSelection::Random(0) {
+        return Err(err_msg("At least one scoop per nonce has to be compared"));
+    }
+    let nonces = plot_file.info().nonces();
+    let mut nonce_indices = if samples >= nonces {
+        (0..nonces).collect()
+    } else {
+        let mut nonce_indices: Vec<u64> = sample_indices(&mut rand::thread_rng(), nonces as usize, samples as usize)
+            .into_iter().map(|index| index as u64).collect();
+        nonce_indices.push(0);
+        nonce_indices.push(nonces - 1);
+        nonce_indices
+    };
+    nonce_indices.sort();
+    nonce_indices.dedup();
+
+    verify_nonces(plot_file, &nonce_indices, scoops)
+}
+
+/// Quick check of a plot file after it was rewritten, returns an error if any sample is corrupted
+pub fn spot_check(plot_file: &mut PlotFile) -> Result<(), Error> {
+    let report = verify_plot_file(plot_file, SPOT_CHECK_SAMPLES, ScoopSelection::Random(SPOT_CHECK_SCOOPS))?;
+    match report.corrupted.first() {
+        None => Ok(()),
+        Some(range) => Err(err_msg(format!("{} is corrupted around nonces {} to {}", plot_file.path().display(),
+                                           range.first_nonce, range.last_nonce))),
+    }
+}
+
+/// Compare the nonces with the given (sorted) indices, regenerating one nonce per thread at a time
+fn verify_nonces(plot_file: &mut PlotFile, nonce_indices: &[u64], scoops: ScoopSelection) -> Result<VerifyReport, Error> {
+    if nonce_indices.is_empty() {
+        // without nonces there is no last nonce to bound the corrupted ranges
+        return Ok(VerifyReport { checked_nonces: 0, checked_scoops: 0, corrupted: Vec::new() });
+    }
+    let info = *plot_file.info();
+    let mut rng = rand::thread_rng();
+    let mut results = Vec::with_capacity(nonce_indices.len());
+    let mut checked_scoops = 0;
+
+    for batch in nonce_indices.chunks(rayon::current_num_threads()) {
+        let mut buffers = vec![vec![0u8; GEN_SIZE]; batch.len()];
+        buffers.par_iter_mut().zip(batch.par_iter())
+            .for_each(|(buffer, &nonce_index)| generate_nonce(info.account_id(), info.start_nonce() + nonce_index, info.format(), buffer));
+
+        for (buffer, &nonce_index) in buffers.iter().zip(batch) {
+            let scoop_numbers: Vec<u16> = match scoops {
+                ScoopSelection::All => (0..SCOOPS_PER_PLOT).collect(),
+                ScoopSelection::Random(count) => {
+                    let count = min(count, SCOOPS_PER_PLOT as usize);
+                    sample_indices(&mut rng, SCOOPS_PER_PLOT as usize, count).into_iter().map(|index| index as u16).collect()
+                },
+            };
+
+            let mut intact = true;
+            for scoop_number in scoop_numbers {
+                let offset = scoop_offset(scoop_number)
+                    .ok_or_else(|| err_msg(format!("Scoop number {} is out of range", scoop_number)))?;
+                checked_scoops += 1;
+                if plot_file.read_scoop_range(scoop_number, nonce_index, 1)?.bytes() != &buffer[offset..offset + SCOOP_SIZE] {
+                    intact = false;
+                    break;
+                }
+            }
+            results.push((info.start_nonce() + nonce_index, intact));
+        }
+    }
+
+    Ok(VerifyReport {
+        checked_nonces: results.len() as u64,
+        checked_scoops,
+        corrupted: corrupted_ranges(&results, info.start_nonce(), info.start_nonce() + info.nonces() - 1),
+    })
+}
+
+/// Merge consecutive corrupted samples into ranges, bounded by the surrounding intact samples
+fn corrupted_ranges(results: &[(u64, bool)], first_nonce: u64, last_nonce: u64) -> Vec<CorruptedRange> {
+    let mut ranges = Vec::new();
+    let mut current: Option<CorruptedRange> = None;
+    let mut last_intact = None;
+
+    for &(nonce, intact) in results {
+        if intact {
+            if let Some(mut range) = current.take() {
+                range.possible_last_nonce = nonce - 1;
+                ranges.push(range);
+            }
+            last_intact = Some(nonce);
+        } else {
+            match current {
+                Some(ref mut range) => range.last_nonce = nonce,
+                None => current = Some(CorruptedRange {
+                    first_nonce: nonce,
+                    last_nonce: nonce,
+                    possible_first_nonce: last_intact.map(|intact| intact + 1).unwrap_or(first_nonce),
+                    possible_last_nonce: last_nonce,
+                }),
+            }
+        }
+    }
+    ranges.extend(current);
+    ranges
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use plot::{PlotFormat, PlotResult};
+    use plot::writer::{PlotWriter, file_offset};
+
+    #[test]
+    fn merges_corrupted_samples() {
+        let results = [(10, false), (12, true), (15, false), (17, false), (20, true), (25, false)];
+        assert_eq!(corrupted_ranges(&results, 10, 29), vec![
+            CorruptedRange { first_nonce: 10, last_nonce: 10, possible_first_nonce: 10, possible_last_nonce: 11 },
+            CorruptedRange { first_nonce: 15, last_nonce: 17, possible_first_nonce: 13, possible_last_nonce: 19 },
+            CorruptedRange { first_nonce: 25, last_nonce: 25, possible_first_nonce: 21, possible_last_nonce: 29 },
+        ]);
+        assert!(corrupted_ranges(&[(10, true), (11, true)], 10, 11).is_empty());
+    }
+
+    #[test]
+    fn finds_corrupted_nonces() {
+        let directory = env::temp_dir().join(format!("burst-rust-verify-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut data = vec![0u8; 6 * GEN_SIZE];
+        for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+            generate_nonce(3, 40 + index as u64, PlotFormat::PoC2, nonce);
+        }
+        let mut writer = PlotWriter::create(&directory, 3, 40, 6, None, PlotFormat::PoC2, 6).unwrap();
+        writer.write(&PlotResult::from_bytes(40, data, PlotFormat::PoC2).unwrap()).unwrap();
+        let path = writer.finish().unwrap();
+
+        let intact = verify_plot_file(&mut PlotFile::open(&path).unwrap(), 6, ScoopSelection::All).unwrap();
+        assert!(intact.is_intact());
+        assert_eq!(intact.checked_nonces, 6);
+        assert_eq!(intact.checked_scoops, 6 * SCOOPS_PER_PLOT as u64);
+        spot_check(&mut PlotFile::open(&path).unwrap()).unwrap();
+
+        // overwrite a byte in late scoops of nonce 43 and 44
+        {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(file_offset(3, 4095, 6) + 63)).unwrap();
+            file.write_all(&[0xff]).unwrap();
+            file.seek(SeekFrom::Start(file_offset(4, 4000, 6))).unwrap();
+            file.write_all(&[0xff]).unwrap();
+        }
+        let report = verify_plot_file(&mut PlotFile::open(&path).unwrap(), 6, ScoopSelection::All).unwrap();
+        // comparing no scoops would report any file as intact
+        let no_scoops = verify_plot_file(&mut PlotFile::open(&path).unwrap(), 6, ScoopSelection::Random(0));
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(no_scoops.is_err());
+
+        assert_eq!(report.corrupted, vec![
+            CorruptedRange { first_nonce: 43, last_nonce: 44, possible_first_nonce: 43, possible_last_nonce: 44 },
+        ]);
+    }
+}
@@ -0,0 +1,374 @@
+use failure::{Error, err_msg};
+use std::cmp::min;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use plot::{PlotFormat, PlotResult};
+use plot::direct_io::DirectFile;
+use plot::file::PlotFileInfo;
+use plot::progress::{sidecar_path, load_checkpoint, save_checkpoint};
+use util::constants::{SCOOPS_PER_PLOT, SCOOP_SIZE, PLOT_SIZE};
+
+/// File name of a plot file: `<accountId>_<startNonce>_<nonces>`, or `<accountId>_<startNonce>_<nonces>_<stagger>`
+/// for the legacy PoC1 naming
+pub fn plot_file_name(account_id: u64, start_nonce: u64, nonces: u64, stagger: Option<u64>) -> String {
+    match stagger {
+        Some(stagger) => format!("{}_{}_{}_{}", account_id, start_nonce, nonces, stagger),
+        None => format!("{}_{}_{}", account_id, start_nonce, nonces),
+    }
+}
+
+/// Plot files in `directory` that were left incomplete and can be continued with `PlotWriter::resume`
+pub fn incomplete_plot_files<P: AsRef<Path>>(directory: P) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().map(|extension| extension == "progress").unwrap_or(false) {
+            let plot_path = path.with_extension("");
+            let is_plot_file = plot_path.file_name().and_then(|name| name.to_str())
+                .map(|name| PlotFileInfo::parse(name).is_ok()).unwrap_or(false);
+            if is_plot_file && plot_path.exists() {
+                paths.push(plot_path);
+            }
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Byte offset of a scoop of the nonce with the given index inside a plot file. Scoop numbers
+/// beyond the last scoop give offsets beyond the stagger group of the nonce.
+pub fn file_offset(nonce_index: u64, scoop_number: u16, stagger: u64) -> u64 {
+    let group = nonce_index / stagger;
+    group * stagger * PLOT_SIZE as u64
+        + scoop_number as u64 * SCOOP_SIZE as u64 * stagger
+        + (nonce_index % stagger) * SCOOP_SIZE as u64
+}
+
+/// Writes consecutive nonces to a plot file.
+///
+/// Nonces come in nonce-major order from a `NonceComputer`, plot files are scoop-major within each
+/// stagger group. Nonces are transposed into a scoop-major buffer that is written with one large
+/// write per scoop once it is full.
+///
+/// Until the file is finished, the number of nonces already on disk is kept in a `.progress` file
+/// next to it. Incomplete files are not opened for mining and can be continued with `resume`.
+pub struct PlotWriter {
+    file: DirectFile,
+    path: PathBuf,
+    progress_path: PathBuf,
+    saved_nonces: u64,
+    account_id: u64,
+    start_nonce: u64,
+    nonces: u64,
+    stagger: u64,
+    format: PlotFormat,
+    buffer: Vec<u8>,
+    buffer_nonces: u64,
+    buffer_start: u64,
+    buffered: u64,
+}
+
+impl PlotWriter {
+    /// Create and preallocate a plot file in `directory`.
+    ///
+    /// `stagger` of `None` writes an optimized file. Legacy staggered files are PoC1 only and the
+    /// number of nonces has to be a multiple of the stagger.
+    pub fn create<P: AsRef<Path>>(directory: P, account_id: u64, start_nonce: u64, nonces: u64, stagger: Option<u64>,
+                                  format: PlotFormat, buffer_nonces: u64) -> Result<Self, Error> {
+        if nonces == 0 || buffer_nonces == 0 {
+            return Err(err_msg("Plot files need at least one nonce and a buffer for one nonce"));
+        }
+        let name_stagger = match (format, stagger) {
+            (PlotFormat::PoC1, stagger) => Some(stagger.unwrap_or(nonces)),
+            (PlotFormat::PoC2, None) => None,
+            (PlotFormat::PoC2, Some(stagger)) if stagger == nonces => None,
+            (PlotFormat::PoC2, Some(_)) => return Err(err_msg("PoC2 plot files have to be optimized")),
+        };
+        let stagger = stagger.unwrap_or(nonces);
+        if stagger == 0 || nonces % stagger != 0 {
+            return Err(err_msg(format!("Number of nonces {} is not a multiple of stagger {}", nonces, stagger)));
+        }
+
+        let path = directory.as_ref().join(plot_file_name(account_id, start_nonce, nonces, name_stagger));
+        let progress_path = sidecar_path(&path, "progress");
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)
+            .map_err(|e| err_msg(format!("Could not create {}: {}", path.display(), e)))?;
+        save_checkpoint(&progress_path, 0)?;
+        preallocate(&file, nonces * PLOT_SIZE as u64)?;
+
+        Self::with_progress(file, path, progress_path, account_id, start_nonce, nonces, stagger, format, buffer_nonces, 0)
+    }
+
+    /// Continue an incomplete plot file after the last nonce that reached the disk.
+    /// Nonces are generated deterministically, so the continued file is the same as one written in a single run.
+    pub fn resume<P: AsRef<Path>>(path: P, buffer_nonces: u64) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let info = {
+            let file_name = path.file_name().and_then(|name| name.to_str())
+                .ok_or_else(|| err_msg(format!("{} is not a plot file", path.display())))?;
+            PlotFileInfo::parse(file_name)?
+        };
+        let progress_path = sidecar_path(&path, "progress");
+        let written_nonces = load_checkpoint(&progress_path)?
+            .ok_or_else(|| err_msg(format!("{} has no progress to resume", path.display())))?;
+        if written_nonces > info.nonces() || buffer_nonces == 0 {
+            return Err(err_msg(format!("Cannot resume {} after {} nonces", path.display(), written_nonces)));
+        }
+
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        Self::with_progress(file, path, progress_path, info.account_id(), info.start_nonce(), info.nonces(), info.stagger(), info.format(),
+                            buffer_nonces, written_nonces)
+    }
+
+    fn with_progress(file: File, path: PathBuf, progress_path: PathBuf, account_id: u64, start_nonce: u64, nonces: u64, stagger: u64,
+                     format: PlotFormat, buffer_nonces: u64, written_nonces: u64) -> Result<Self, Error> {
+        let buffer_nonces = min(buffer_nonces, stagger);
+
+        Ok(Self {
+            file: DirectFile::new(file, &path, true),
+            path,
+            progress_path,
+            saved_nonces: written_nonces,
+            account_id,
+            start_nonce,
+            nonces,
+            stagger,
+            format,
+            buffer: vec![0u8; buffer_nonces as usize * PLOT_SIZE],
+            buffer_nonces,
+            buffer_start: written_nonces,
+            buffered: 0,
+        })
+    }
+
+    /// Write the file with O_DIRECT to keep the page cache clean. Returns false if the file
+    /// system does not support it and buffered writes are used instead.
+    pub fn enable_direct_io(&mut self) -> Result<bool, Error> {
+        self.file.enable_direct_io()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn account_id(&self) -> u64 {
+        self.account_id
+    }
+
+    pub fn format(&self) -> PlotFormat {
+        self.format
+    }
+
+    /// Next nonce the writer expects
+    pub fn next_nonce(&self) -> u64 {
+        self.start_nonce + self.buffer_start + self.buffered
+    }
+
+    /// Nonces that still need to be written
+    pub fn remaining_nonces(&self) -> u64 {
+        self.nonces - self.buffer_start - self.buffered
+    }
+
+    /// Add the nonces of a plot result, which has to continue where the last one ended.
+    /// Nonces beyond the end of the file are ignored. Afterwards the progress of all nonces
+    /// that were flushed is recorded.
+    pub fn write(&mut self, plot: &PlotResult) -> Result<(), Error> {
+        if plot.format() != self.format {
+            return Err(err_msg(format!("Cannot write {:?} nonces to a {:?} plot file", plot.format(), self.format)));
+        }
+        if plot.start_nonce() != self.next_nonce() {
+            return Err(err_msg(format!("Expected nonce {} but got {}", self.next_nonce(), plot.start_nonce())));
+        }
+
+        for nonce in plot.nonces().take(self.remaining_nonces() as usize) {
+            for (scoop_number, scoop) in nonce.scoops().enumerate() {
+                let offset = scoop_number * SCOOP_SIZE * self.buffer_nonces as usize + self.buffered as usize * SCOOP_SIZE;
+                self.buffer[offset..offset + SCOOP_SIZE].copy_from_slice(scoop.bytes());
+            }
+            self.buffered += 1;
+
+            let group_end = (self.buffer_start + self.buffered) % self.stagger == 0;
+            if self.buffered == self.buffer_nonces || group_end {
+                self.flush()?;
+            }
+        }
+        self.save_progress()
+    }
+
+    /// Flush all buffered nonces, sync the file to disk and mark it complete
+    pub fn finish(mut self) -> Result<PathBuf, Error> {
+        self.flush()?;
+        self.file.file().sync_all()?;
+        fs::remove_file(&self.progress_path)?;
+        Ok(self.path)
+    }
+
+    fn save_progress(&mut self) -> Result<(), Error> {
+        if self.buffer_start == self.saved_nonces {
+            return Ok(());
+        }
+        // the progress must never get ahead of the data
+        self.file.file().sync_data()?;
+        save_checkpoint(&self.progress_path, self.buffer_start)?;
+        self.saved_nonces = self.buffer_start;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+
+        let len = self.buffered as usize * SCOOP_SIZE;
+        for scoop_number in 0..SCOOPS_PER_PLOT {
+            let offset = scoop_number as usize * SCOOP_SIZE * self.buffer_nonces as usize;
+            self.file.write_at(file_offset(self.buffer_start, scoop_number, self.stagger), &self.buffer[offset..offset + len])?;
+        }
+
+        self.buffer_start += self.buffered;
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+/// Reserve `len` bytes on disk for a new plot file
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> Result<(), Error> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    // not every file system supports fallocate, a sparse file is better than nothing
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } != 0 {
+        file.set_len(len)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(file: &File, len: u64) -> Result<(), Error> {
+    file.set_len(len)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use util::constants::GEN_SIZE;
+
+    fn test_plot(start_nonce: u64, nonces: u64) -> PlotResult {
+        let mut data = vec![0u8; nonces as usize * GEN_SIZE];
+        for (index, nonce) in data.chunks_mut(GEN_SIZE).enumerate() {
+            for (scoop_number, scoop) in nonce[..PLOT_SIZE].chunks_mut(SCOOP_SIZE).enumerate() {
+                scoop[0] = (start_nonce + index as u64) as u8;
+                scoop[1] = scoop_number as u8;
+                scoop[2] = (scoop_number >> 8) as u8;
+            }
+        }
+        PlotResult::from_bytes(start_nonce, data, PlotFormat::PoC1).unwrap()
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(plot_file_name(123, 0, 8192, None), "123_0_8192");
+        assert_eq!(plot_file_name(123, 100, 8192, Some(4096)), "123_100_8192_4096");
+    }
+
+    #[test]
+    fn writes_staggered_scoop_major_layout() {
+        let directory = env::temp_dir().join(format!("burst-rust-writer-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut writer = PlotWriter::create(&directory, 1, 10, 4, Some(2), PlotFormat::PoC1, 8).unwrap();
+        writer.write(&test_plot(10, 3)).unwrap();
+        assert_eq!(writer.remaining_nonces(), 1);
+        writer.write(&test_plot(13, 3)).unwrap();
+        let path = writer.finish().unwrap();
+
+        assert_eq!(path.file_name().unwrap(), "1_10_4_2");
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(data.len(), 4 * PLOT_SIZE);
+        for nonce_index in 0..4u64 {
+            for &scoop_number in &[0u16, 1, 4095] {
+                let offset = file_offset(nonce_index, scoop_number, 2) as usize;
+                assert_eq!(data[offset], 10 + nonce_index as u8);
+                assert_eq!(data[offset + 1], scoop_number as u8);
+                assert_eq!(data[offset + 2], (scoop_number >> 8) as u8);
+            }
+        }
+        // second stagger group starts after the first two nonces
+        assert_eq!(data[2 * PLOT_SIZE], 12);
+        assert_eq!(data[SCOOP_SIZE * 2], 10);
+    }
+
+    #[test]
+    fn resumes_after_last_flushed_nonce() {
+        let directory = env::temp_dir().join(format!("burst-rust-resume-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut writer = PlotWriter::create(&directory, 1, 10, 6, Some(2), PlotFormat::PoC1, 2).unwrap();
+        let path = writer.path().to_path_buf();
+        // the third nonce stays in the buffer and is lost with the process
+        writer.write(&test_plot(10, 3)).unwrap();
+        drop(writer);
+
+        assert!(PlotWriter::create(&directory, 1, 10, 6, Some(2), PlotFormat::PoC1, 2).is_err());
+        assert_eq!(incomplete_plot_files(&directory).unwrap(), vec![path.clone()]);
+        assert!(::plot::file::PlotFile::open(&path).is_err());
+
+        let mut writer = PlotWriter::resume(&path, 2).unwrap();
+        assert_eq!(writer.next_nonce(), 12);
+        assert_eq!(writer.remaining_nonces(), 4);
+        writer.write(&test_plot(12, 4)).unwrap();
+        writer.finish().unwrap();
+
+        assert!(incomplete_plot_files(&directory).unwrap().is_empty());
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        for nonce_index in 0..6u64 {
+            let offset = file_offset(nonce_index, 4095, 2) as usize;
+            assert_eq!(data[offset], 10 + nonce_index as u8);
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_with_direct_io() {
+        let directory = env::temp_dir().join(format!("burst-rust-writer-direct-{}", ::std::process::id()));
+        let mut files = Vec::new();
+        for &direct_io in &[false, true] {
+            let directory = directory.join(direct_io.to_string());
+            fs::create_dir_all(&directory).unwrap();
+            // 3 nonces per flush leave every scoop row unaligned
+            let mut writer = PlotWriter::create(&directory, 1, 0, 8, None, PlotFormat::PoC1, 3).unwrap();
+            if direct_io {
+                writer.enable_direct_io().unwrap();
+            }
+            writer.write(&test_plot(0, 5)).unwrap();
+            writer.write(&test_plot(5, 3)).unwrap();
+            let path = writer.finish().unwrap();
+
+            let mut plot_file = ::plot::file::PlotFile::open(&path).unwrap();
+            if direct_io {
+                plot_file.enable_direct_io().unwrap();
+            }
+            let scoops = plot_file.read_scoop_range(4095, 1, 6).unwrap();
+            assert_eq!(scoops.bytes()[0], 1);
+            assert_eq!(scoops.bytes()[5 * SCOOP_SIZE], 6);
+
+            let mut data = Vec::new();
+            File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+            files.push(data);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(files[0], files[1]);
+    }
+}
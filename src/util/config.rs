@@ -1,6 +1,8 @@
-use config::{Config, File};
+use config::{Config, ConfigError, File};
+use std::path::Path;
 
 use util::deserialization::from_str;
+use plot::PlotFormat;
 
 lazy_static! {
     pub static ref CONFIG: Config = {
@@ -11,30 +13,126 @@ lazy_static! {
     };
 }
 
+/// Where nonces get computed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    OpenCl,
+    Cpu,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::OpenCl
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct DeviceConfig {
+    #[serde(default)]
+    backend: Backend,
+    #[serde(default)]
+    all_devices: bool,
+    #[serde(default)]
     platform_id: u32,
+    #[serde(default)]
     device_id: u32,
+    #[serde(default)]
+    auto_tune: bool,
+    #[serde(default)]
     global_work_size: u32,
+    #[serde(default)]
     local_work_size: u32,
-    hashes_number: u32,
+    #[serde(default = "default_buffers")]
+    buffers: usize,
+    kernel_dir: Option<String>,
+}
+
+fn default_buffers() -> usize {
+    2
 }
 
 impl DeviceConfig {
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+    /// Use every device of every OpenCL platform, ignoring `platform_id` and `device_id`
+    pub fn all_devices(&self) -> bool {
+        self.all_devices
+    }
     pub fn platform_id(&self) -> u32 {
         self.platform_id
     }
     pub fn device_id(&self) -> u32 {
         self.device_id
     }
+    /// Benchmark the device to find the work sizes instead of using `global_work_size` and `local_work_size`
+    pub fn auto_tune(&self) -> bool {
+        self.auto_tune
+    }
     pub fn global_work_size(&self) -> u32 {
         self.global_work_size
     }
     pub fn local_work_size(&self) -> u32 {
         self.local_work_size
     }
-    pub fn hashes_number(&self) -> u32 {
-        self.hashes_number
+    /// Batches in flight on the OpenCL device, each with its own device buffers
+    pub fn buffers(&self) -> usize {
+        self.buffers
+    }
+    /// Directory with kernel files that replace the kernels compiled into the crate
+    pub fn kernel_dir(&self) -> Option<&Path> {
+        self.kernel_dir.as_ref().map(Path::new)
+    }
+    /// The same config with fixed work sizes
+    pub fn with_work_sizes(&self, global_work_size: u32, local_work_size: u32) -> Self {
+        Self { auto_tune: false, global_work_size, local_work_size, ..self.clone() }
+    }
+}
+
+/// The `[[device]]` entries of the config, a single `[device]` table is accepted as well
+pub fn device_configs(config: &Config) -> Result<Vec<DeviceConfig>, ConfigError> {
+    let device_configs = config.get::<Vec<DeviceConfig>>("device")
+        .or_else(|_| config.get::<DeviceConfig>("device").map(|device_config| vec![device_config]))?;
+    // every batch in flight needs its own buffers
+    if device_configs.iter().any(|device_config| device_config.buffers() == 0) {
+        return Err(ConfigError::Message(String::from("buffers of a device has to be at least 1")));
+    }
+    Ok(device_configs)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CpuConfig {
+    nonces_per_batch: u64,
+}
+
+impl CpuConfig {
+    pub fn nonces_per_batch(&self) -> u64 {
+        self.nonces_per_batch
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiskConfig {
+    directories: Vec<String>,
+    read_buffer_nonces: u64,
+    hashing_threads: usize,
+    #[serde(default)]
+    direct_io: bool,
+}
+
+impl DiskConfig {
+    pub fn directories(&self) -> &[String] {
+        &self.directories
+    }
+    pub fn read_buffer_nonces(&self) -> u64 {
+        self.read_buffer_nonces
+    }
+    pub fn hashing_threads(&self) -> usize {
+        self.hashing_threads
+    }
+    pub fn direct_io(&self) -> bool {
+        self.direct_io
     }
 }
 
@@ -49,6 +147,8 @@ pub struct WorkConfig {
     target_deadline: u64,
     submission_retry_number: u64,
     submission_retry_interval_seconds: u64,
+    #[serde(default)]
+    plot_format: PlotFormat,
 }
 
 impl WorkConfig {
@@ -73,4 +173,56 @@ impl WorkConfig {
     pub fn submission_retry_interval_seconds(&self) -> u64 {
         self.submission_retry_interval_seconds
     }
+    pub fn plot_format(&self) -> PlotFormat {
+        self.plot_format
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::FileFormat;
+
+    fn config(toml: &str) -> Config {
+        let mut config = Config::default();
+        config.merge(File::from_str(toml, FileFormat::Toml)).unwrap();
+        config
+    }
+
+    #[test]
+    fn reads_device_arrays_and_tables() {
+        let devices = device_configs(&config(r#"
+            [[device]]
+            platform_id = 1
+            global_work_size = 8192
+            local_work_size = 256
+
+            [[device]]
+            all_devices = true
+            auto_tune = true
+            buffers = 3
+            kernel_dir = "kernel"
+        "#)).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!((devices[0].platform_id(), devices[0].all_devices(), devices[0].buffers()), (1, false, 2));
+        assert_eq!((devices[1].auto_tune(), devices[1].all_devices(), devices[1].buffers()), (true, true, 3));
+        assert_eq!((devices[0].kernel_dir(), devices[1].kernel_dir()), (None, Some(Path::new("kernel"))));
+
+        let tuned = devices[1].with_work_sizes(4096, 128);
+        assert_eq!((tuned.auto_tune(), tuned.global_work_size(), tuned.local_work_size(), tuned.buffers()), (false, 4096, 128, 3));
+
+        let devices = device_configs(&config(r#"
+            [device]
+            backend = "cpu"
+            global_work_size = 8192
+            local_work_size = 256
+        "#)).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].backend(), Backend::Cpu);
+
+        assert!(device_configs(&config(r#"
+            [device]
+            buffers = 0
+        "#)).is_err());
+    }
 }
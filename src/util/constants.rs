@@ -4,3 +4,6 @@ pub const SCOOP_SIZE: usize = HASHES_PER_SCOOP * HASH_SIZE as usize;
 pub const SCOOPS_PER_PLOT: u16 = 4096;
 pub const PLOT_SIZE: usize = SCOOPS_PER_PLOT as usize * SCOOP_SIZE;
 pub const GEN_SIZE: usize = PLOT_SIZE + 16;
+pub const HASH_CAP: usize = 4096;
+/// First block height at which the chain requires PoC2 plots
+pub const POC2_START_HEIGHT: u64 = 502000;
@@ -1,4 +1,5 @@
 pub mod config;
 pub mod constants;
 pub mod sph_shabal;
+pub mod simd_shabal;
 pub mod deserialization;
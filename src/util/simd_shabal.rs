@@ -0,0 +1,408 @@
+//! Multi-lane Shabal-256 specialised for deadline calculation.
+//!
+//! A deadline hash always consumes the 32 byte generation signature followed by 64 bytes of scoop
+//! data, so the message schedule is fixed: one full block and one final block holding the last
+//! 32 scoop bytes. Several scoops are hashed side by side in SSE2 (4 lanes) or AVX2 (8 lanes)
+//! registers, picked at runtime. Remaining scoops and other CPUs use the scalar lane type.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use util::constants::SCOOP_SIZE;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+const A_INIT: [u32; 12] = [
+    0x52F84552, 0xE54B7999, 0x2D8EE3EC, 0xB9645191,
+    0xE0078B86, 0xBB7C44C9, 0xD2B5C1CA, 0xB0D2EB8C,
+    0x14CE5A45, 0x22AF50DC, 0xEFFDBC6B, 0xEB21B74A,
+];
+
+const B_INIT: [u32; 16] = [
+    0xB555C6EE, 0x3E710596, 0xA72A652F, 0x9301515F,
+    0xDA28C1FA, 0x696FD868, 0x9CB6BF72, 0x0AFE4002,
+    0xA6E03615, 0x5138C1D4, 0xBE216306, 0xB38B8890,
+    0x3EA8B96B, 0x3299ACE4, 0x30924DD4, 0x55CB34A5,
+];
+
+const C_INIT: [u32; 16] = [
+    0xB405F031, 0xC4233EBA, 0xB3733979, 0xC0DD9D55,
+    0xC51C28AE, 0xA327B8E1, 0x56C56167, 0xED614433,
+    0x88B59D60, 0x60E2CEBA, 0x758B4B8B, 0x83E82A7F,
+    0xBC968828, 0xE6E00BF7, 0xBA839E55, 0x9B491C60,
+];
+
+const MAX_LANES: usize = 8;
+
+/// Calculate the deadlines of many scoops for the same generation signature.
+///
+/// The result matches `ScoopData::calculate_deadline` for every scoop.
+pub fn calculate_deadlines(generation_signature: &[u8], scoops: &[&[u8; SCOOP_SIZE]], base_target: u64) -> Vec<u64> {
+    let mut gensig = [0u32; 8];
+    LittleEndian::read_u32_into(&generation_signature[..32], &mut gensig);
+
+    let mut deadlines = vec![0u64; scoops.len()];
+
+    let hashed = hash_simd(&gensig, scoops, &mut deadlines);
+    unsafe { hash_groups::<u32>(&gensig, &scoops[hashed..], &mut deadlines[hashed..]); }
+
+    for deadline in deadlines.iter_mut() {
+        *deadline /= base_target;
+    }
+    deadlines
+}
+
+/// Hash as many scoops as fit into whole SIMD groups, returns the number of scoops hashed
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn hash_simd(gensig: &[u32; 8], scoops: &[&[u8; SCOOP_SIZE]], targets: &mut [u64]) -> usize {
+    if is_x86_feature_detected!("avx2") {
+        unsafe { hash_avx2(gensig, scoops, targets) }
+    } else if is_x86_feature_detected!("sse2") {
+        unsafe { hash_sse2(gensig, scoops, targets) }
+    } else {
+        0
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn hash_simd(_gensig: &[u32; 8], _scoops: &[&[u8; SCOOP_SIZE]], _targets: &mut [u64]) -> usize {
+    0
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn hash_avx2(gensig: &[u32; 8], scoops: &[&[u8; SCOOP_SIZE]], targets: &mut [u64]) -> usize {
+    hash_groups::<__m256i>(gensig, scoops, targets)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn hash_sse2(gensig: &[u32; 8], scoops: &[&[u8; SCOOP_SIZE]], targets: &mut [u64]) -> usize {
+    hash_groups::<__m128i>(gensig, scoops, targets)
+}
+
+/// Write the 64 bit targets of all whole groups of `V::LANES` scoops, returns the number of scoops hashed
+#[inline(always)]
+unsafe fn hash_groups<V: Lanes>(gensig: &[u32; 8], scoops: &[&[u8; SCOOP_SIZE]], targets: &mut [u64]) -> usize {
+    let hashed = scoops.len() / V::LANES * V::LANES;
+
+    for (group, targets) in scoops[..hashed].chunks(V::LANES).zip(targets[..hashed].chunks_mut(V::LANES)) {
+        let (low, high) = hash_lanes::<V>(gensig, group);
+
+        let mut low_words = [0u32; MAX_LANES];
+        let mut high_words = [0u32; MAX_LANES];
+        low.scatter(&mut low_words);
+        high.scatter(&mut high_words);
+
+        for (lane, target) in targets.iter_mut().enumerate() {
+            *target = low_words[lane] as u64 | (high_words[lane] as u64) << 32;
+        }
+    }
+    hashed
+}
+
+/// Shabal-256 of `gensig || scoop` for `V::LANES` scoops, returns the first two output words
+#[inline(always)]
+unsafe fn hash_lanes<V: Lanes>(gensig: &[u32; 8], scoops: &[&[u8; SCOOP_SIZE]]) -> (V, V) {
+    let mut state = State::<V>::new();
+
+    // first block: generation signature and first half of the scoop
+    let mut m = [V::splat(0); 16];
+    for i in 0..8 {
+        m[i] = V::splat(gensig[i]);
+        m[i + 8] = scoop_word::<V>(scoops, i);
+    }
+    state.input_block_add(&m);
+    state.xor_w(1);
+    state.apply_p(&m);
+    state.input_block_sub(&m);
+    state.swap_bc();
+
+    // final block: second half of the scoop and padding
+    let mut m = [V::splat(0); 16];
+    for i in 0..8 {
+        m[i] = scoop_word::<V>(scoops, i + 8);
+    }
+    m[8] = V::splat(0x80);
+    state.input_block_add(&m);
+    state.xor_w(2);
+    state.apply_p(&m);
+    for _ in 0..3 {
+        state.swap_bc();
+        state.xor_w(2);
+        state.apply_p(&m);
+    }
+
+    (state.b[8], state.b[9])
+}
+
+#[inline(always)]
+unsafe fn scoop_word<V: Lanes>(scoops: &[&[u8; SCOOP_SIZE]], word: usize) -> V {
+    let mut values = [0u32; MAX_LANES];
+    for (value, scoop) in values.iter_mut().zip(scoops.iter()) {
+        *value = LittleEndian::read_u32(&scoop[word * 4..word * 4 + 4]);
+    }
+    V::gather(&values)
+}
+
+struct State<V: Lanes> {
+    a: [V; 12],
+    b: [V; 16],
+    c: [V; 16],
+}
+
+impl<V: Lanes> State<V> {
+    #[inline(always)]
+    unsafe fn new() -> Self {
+        let mut state = Self { a: [V::splat(0); 12], b: [V::splat(0); 16], c: [V::splat(0); 16] };
+        for i in 0..12 {
+            state.a[i] = V::splat(A_INIT[i]);
+        }
+        for i in 0..16 {
+            state.b[i] = V::splat(B_INIT[i]);
+            state.c[i] = V::splat(C_INIT[i]);
+        }
+        state
+    }
+
+    #[inline(always)]
+    unsafe fn input_block_add(&mut self, m: &[V; 16]) {
+        for i in 0..16 {
+            self.b[i] = self.b[i].add(m[i]);
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn input_block_sub(&mut self, m: &[V; 16]) {
+        for i in 0..16 {
+            self.c[i] = self.c[i].sub(m[i]);
+        }
+    }
+
+    /// The block counter never exceeds 32 bits for our two block messages, so the high word stays 0
+    #[inline(always)]
+    unsafe fn xor_w(&mut self, w_low: u32) {
+        self.a[0] = self.a[0].xor(V::splat(w_low));
+    }
+
+    #[inline(always)]
+    unsafe fn swap_bc(&mut self) {
+        ::std::mem::swap(&mut self.b, &mut self.c);
+    }
+
+    #[inline(always)]
+    unsafe fn apply_p(&mut self, m: &[V; 16]) {
+        for i in 0..16 {
+            self.b[i] = self.b[i].rotl(17);
+        }
+
+        for step in 0..3 {
+            for i in 0..16 {
+                let xa0 = (16 * step + i) % 12;
+                let xa1 = (16 * step + i + 11) % 12;
+
+                let a1 = self.a[xa1].rotl(15);
+                let a1 = a1.add(a1.shl(2)); // * 5
+                let a0 = self.a[xa0].xor(a1).xor(self.c[(24 - i) % 16]);
+                let a0 = a0.add(a0.shl(1)); // * 3
+                let a0 = a0.xor(self.b[(i + 13) % 16])
+                    .xor(self.b[(i + 6) % 16].andnot(self.b[(i + 9) % 16]))
+                    .xor(m[i]);
+
+                self.a[xa0] = a0;
+                self.b[i] = self.b[i].rotl(1).xor(a0).not();
+            }
+        }
+
+        for k in 0..36 {
+            self.a[11 - k % 12] = self.a[11 - k % 12].add(self.c[(54 - k) % 16]);
+        }
+    }
+}
+
+/// 32 bit integer operations applied to all lanes at once
+trait Lanes: Copy {
+    const LANES: usize;
+
+    unsafe fn splat(value: u32) -> Self;
+    /// Load the first `LANES` values
+    unsafe fn gather(values: &[u32; MAX_LANES]) -> Self;
+    /// Store into the first `LANES` values
+    unsafe fn scatter(self, values: &mut [u32; MAX_LANES]);
+    unsafe fn add(self, other: Self) -> Self;
+    unsafe fn sub(self, other: Self) -> Self;
+    unsafe fn xor(self, other: Self) -> Self;
+    unsafe fn or(self, other: Self) -> Self;
+    /// `!self & other`
+    unsafe fn andnot(self, other: Self) -> Self;
+    unsafe fn shl(self, bits: i32) -> Self;
+    unsafe fn shr(self, bits: i32) -> Self;
+
+    #[inline(always)]
+    unsafe fn not(self) -> Self {
+        self.xor(Self::splat(0xFFFF_FFFF))
+    }
+
+    #[inline(always)]
+    unsafe fn rotl(self, bits: i32) -> Self {
+        self.shl(bits).or(self.shr(32 - bits))
+    }
+}
+
+impl Lanes for u32 {
+    const LANES: usize = 1;
+
+    #[inline(always)]
+    unsafe fn splat(value: u32) -> Self { value }
+    #[inline(always)]
+    unsafe fn gather(values: &[u32; MAX_LANES]) -> Self { values[0] }
+    #[inline(always)]
+    unsafe fn scatter(self, values: &mut [u32; MAX_LANES]) { values[0] = self; }
+    #[inline(always)]
+    unsafe fn add(self, other: Self) -> Self { self.wrapping_add(other) }
+    #[inline(always)]
+    unsafe fn sub(self, other: Self) -> Self { self.wrapping_sub(other) }
+    #[inline(always)]
+    unsafe fn xor(self, other: Self) -> Self { self ^ other }
+    #[inline(always)]
+    unsafe fn or(self, other: Self) -> Self { self | other }
+    #[inline(always)]
+    unsafe fn andnot(self, other: Self) -> Self { !self & other }
+    #[inline(always)]
+    unsafe fn shl(self, bits: i32) -> Self { self << bits }
+    #[inline(always)]
+    unsafe fn shr(self, bits: i32) -> Self { self >> bits }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Lanes for __m128i {
+    const LANES: usize = 4;
+
+    #[inline(always)]
+    unsafe fn splat(value: u32) -> Self { _mm_set1_epi32(value as i32) }
+    #[inline(always)]
+    unsafe fn gather(values: &[u32; MAX_LANES]) -> Self {
+        _mm_loadu_si128(values.as_ptr() as *const __m128i)
+    }
+    #[inline(always)]
+    unsafe fn scatter(self, values: &mut [u32; MAX_LANES]) {
+        _mm_storeu_si128(values.as_mut_ptr() as *mut __m128i, self)
+    }
+    #[inline(always)]
+    unsafe fn add(self, other: Self) -> Self { _mm_add_epi32(self, other) }
+    #[inline(always)]
+    unsafe fn sub(self, other: Self) -> Self { _mm_sub_epi32(self, other) }
+    #[inline(always)]
+    unsafe fn xor(self, other: Self) -> Self { _mm_xor_si128(self, other) }
+    #[inline(always)]
+    unsafe fn or(self, other: Self) -> Self { _mm_or_si128(self, other) }
+    #[inline(always)]
+    unsafe fn andnot(self, other: Self) -> Self { _mm_andnot_si128(self, other) }
+    #[inline(always)]
+    unsafe fn shl(self, bits: i32) -> Self { _mm_sll_epi32(self, _mm_cvtsi32_si128(bits)) }
+    #[inline(always)]
+    unsafe fn shr(self, bits: i32) -> Self { _mm_srl_epi32(self, _mm_cvtsi32_si128(bits)) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Lanes for __m256i {
+    const LANES: usize = 8;
+
+    #[inline(always)]
+    unsafe fn splat(value: u32) -> Self { _mm256_set1_epi32(value as i32) }
+    #[inline(always)]
+    unsafe fn gather(values: &[u32; MAX_LANES]) -> Self {
+        _mm256_loadu_si256(values.as_ptr() as *const __m256i)
+    }
+    #[inline(always)]
+    unsafe fn scatter(self, values: &mut [u32; MAX_LANES]) {
+        _mm256_storeu_si256(values.as_mut_ptr() as *mut __m256i, self)
+    }
+    #[inline(always)]
+    unsafe fn add(self, other: Self) -> Self { _mm256_add_epi32(self, other) }
+    #[inline(always)]
+    unsafe fn sub(self, other: Self) -> Self { _mm256_sub_epi32(self, other) }
+    #[inline(always)]
+    unsafe fn xor(self, other: Self) -> Self { _mm256_xor_si256(self, other) }
+    #[inline(always)]
+    unsafe fn or(self, other: Self) -> Self { _mm256_or_si256(self, other) }
+    #[inline(always)]
+    unsafe fn andnot(self, other: Self) -> Self { _mm256_andnot_si256(self, other) }
+    #[inline(always)]
+    unsafe fn shl(self, bits: i32) -> Self { _mm256_sll_epi32(self, _mm_cvtsi32_si128(bits)) }
+    #[inline(always)]
+    unsafe fn shr(self, bits: i32) -> Self { _mm256_srl_epi32(self, _mm_cvtsi32_si128(bits)) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use util::sph_shabal::shabal256;
+
+    fn reference_deadline(gensig: &[u8], scoop: &[u8; SCOOP_SIZE], base_target: u64) -> u64 {
+        let mut input = gensig.to_vec();
+        input.extend_from_slice(scoop);
+        LittleEndian::read_u64(&shabal256(&input)[0..8]) / base_target
+    }
+
+    fn test_scoops(count: u32) -> Vec<[u8; SCOOP_SIZE]> {
+        (0..count).map(|n| {
+            let mut scoop = [0u8; SCOOP_SIZE];
+            for (i, byte) in scoop.iter_mut().enumerate() {
+                *byte = (n as usize * 31 + i * 17) as u8;
+            }
+            scoop
+        }).collect()
+    }
+
+    #[test]
+    fn matches_scalar_shabal() {
+        let gensig: Vec<u8> = (0..32u32).map(|i| (i * 13 + 7) as u8).collect();
+        let scoops = test_scoops(37);
+        let scoop_refs: Vec<&[u8; SCOOP_SIZE]> = scoops.iter().collect();
+
+        let deadlines = calculate_deadlines(&gensig, &scoop_refs, 43899);
+
+        assert_eq!(deadlines.len(), scoops.len());
+        for (scoop, deadline) in scoops.iter().zip(deadlines.iter()) {
+            assert_eq!(*deadline, reference_deadline(&gensig, scoop, 43899));
+        }
+    }
+
+    /// `calculate_deadlines` only uses the best lanes of the host, so SSE2 is tested on its own
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn sse2_lanes_match_scalar_shabal() {
+        if !is_x86_feature_detected!("sse2") {
+            return;
+        }
+        let gensig: Vec<u8> = (0..32u32).map(|i| (i * 5 + 3) as u8).collect();
+        let scoops = test_scoops(9);
+        let scoop_refs: Vec<&[u8; SCOOP_SIZE]> = scoops.iter().collect();
+        let mut gensig_words = [0u32; 8];
+        LittleEndian::read_u32_into(&gensig, &mut gensig_words);
+
+        let mut targets = [0u64; 9];
+        let hashed = unsafe { hash_sse2(&gensig_words, &scoop_refs, &mut targets) };
+
+        assert_eq!(hashed, 8);
+        for (scoop, target) in scoops.iter().zip(&targets[..hashed]) {
+            assert_eq!(*target, reference_deadline(&gensig, scoop, 1));
+        }
+    }
+
+    #[test]
+    fn scalar_lanes_match_scalar_shabal() {
+        let gensig = [0xA5u8; 32];
+        let scoop = [0x3Cu8; SCOOP_SIZE];
+        let mut gensig_words = [0u32; 8];
+        LittleEndian::read_u32_into(&gensig, &mut gensig_words);
+
+        let mut target = [0u64; 1];
+        unsafe { hash_groups::<u32>(&gensig_words, &[&scoop], &mut target); }
+
+        assert_eq!(target[0], reference_deadline(&gensig, &scoop, 1));
+    }
+}
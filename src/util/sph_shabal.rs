@@ -1,53 +1,232 @@
-extern crate libc;
-
-use std::default::Default;
-
-#[repr(C)]
-pub struct sph_shabal_context {
-    pub buf: [u8; 64],
-    pub ptr: libc::size_t,
-    pub a: [u32; 12],
-    pub b: [u32; 16],
-    pub c: [u32; 16],
-    pub w_high: u32,
-    pub w_low: u32,
+//! Pure Rust port of the Shabal-256 implementation of sphlib.
+
+const A_INIT: [u32; 12] = [
+    0x52F84552, 0xE54B7999, 0x2D8EE3EC, 0xB9645191,
+    0xE0078B86, 0xBB7C44C9, 0xD2B5C1CA, 0xB0D2EB8C,
+    0x14CE5A45, 0x22AF50DC, 0xEFFDBC6B, 0xEB21B74A,
+];
+
+const B_INIT: [u32; 16] = [
+    0xB555C6EE, 0x3E710596, 0xA72A652F, 0x9301515F,
+    0xDA28C1FA, 0x696FD868, 0x9CB6BF72, 0x0AFE4002,
+    0xA6E03615, 0x5138C1D4, 0xBE216306, 0xB38B8890,
+    0x3EA8B96B, 0x3299ACE4, 0x30924DD4, 0x55CB34A5,
+];
+
+const C_INIT: [u32; 16] = [
+    0xB405F031, 0xC4233EBA, 0xB3733979, 0xC0DD9D55,
+    0xC51C28AE, 0xA327B8E1, 0x56C56167, 0xED614433,
+    0x88B59D60, 0x60E2CEBA, 0x758B4B8B, 0x83E82A7F,
+    0xBC968828, 0xE6E00BF7, 0xBA839E55, 0x9B491C60,
+];
+
+const BLOCK_SIZE: usize = 64;
+
+/// Incremental Shabal-256 hasher.
+///
+/// Cloning a hasher snapshots its state, so a common prefix only needs to be absorbed once.
+#[derive(Clone)]
+pub struct Shabal256 {
+    buf: [u8; BLOCK_SIZE],
+    ptr: usize,
+    a: [u32; 12],
+    b: [u32; 16],
+    c: [u32; 16],
+    w_low: u32,
+    w_high: u32,
+}
+
+impl Default for Shabal256 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Default for sph_shabal_context {
-    fn default() -> sph_shabal_context {
-        sph_shabal_context {
-            buf: [0u8; 64],
+impl Shabal256 {
+    pub fn new() -> Self {
+        Self {
+            buf: [0u8; BLOCK_SIZE],
             ptr: 0,
-            a: [0u32; 12],
-            b: [0u32; 16],
-            c: [0u32; 16],
+            a: A_INIT,
+            b: B_INIT,
+            c: C_INIT,
+            w_low: 1,
             w_high: 0,
-            w_low: 0,
         }
     }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let len = ::std::cmp::min(BLOCK_SIZE - self.ptr, data.len());
+            self.buf[self.ptr..self.ptr + len].copy_from_slice(&data[..len]);
+            self.ptr += len;
+            data = &data[len..];
+
+            if self.ptr == BLOCK_SIZE {
+                let m = decode_block(&self.buf);
+                self.input_block_add(&m);
+                self.xor_w();
+                self.apply_p(&m);
+                self.input_block_sub(&m);
+                self.swap_bc();
+                self.incr_w();
+                self.ptr = 0;
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        let ptr = self.ptr;
+        self.buf[ptr] = 0x80;
+        for byte in self.buf[ptr + 1..].iter_mut() {
+            *byte = 0;
+        }
+
+        let m = decode_block(&self.buf);
+        self.input_block_add(&m);
+        self.xor_w();
+        self.apply_p(&m);
+        for _ in 0..3 {
+            self.swap_bc();
+            self.xor_w();
+            self.apply_p(&m);
+        }
+
+        let mut output = [0u8; 32];
+        for (chunk, word) in output.chunks_mut(4).zip(self.b[8..].iter()) {
+            chunk.copy_from_slice(&[*word as u8, (*word >> 8) as u8, (*word >> 16) as u8, (*word >> 24) as u8]);
+        }
+        output
+    }
+
+    fn input_block_add(&mut self, m: &[u32; 16]) {
+        for (b, m) in self.b.iter_mut().zip(m.iter()) {
+            *b = b.wrapping_add(*m);
+        }
+    }
+
+    fn input_block_sub(&mut self, m: &[u32; 16]) {
+        for (c, m) in self.c.iter_mut().zip(m.iter()) {
+            *c = c.wrapping_sub(*m);
+        }
+    }
+
+    fn xor_w(&mut self) {
+        self.a[0] ^= self.w_low;
+        self.a[1] ^= self.w_high;
+    }
+
+    fn swap_bc(&mut self) {
+        ::std::mem::swap(&mut self.b, &mut self.c);
+    }
+
+    fn incr_w(&mut self) {
+        self.w_low = self.w_low.wrapping_add(1);
+        if self.w_low == 0 {
+            self.w_high = self.w_high.wrapping_add(1);
+        }
+    }
+
+    fn apply_p(&mut self, m: &[u32; 16]) {
+        let a = &mut self.a;
+        let b = &mut self.b;
+        let c = &self.c;
+
+        for b in b.iter_mut() {
+            *b = b.rotate_left(17);
+        }
+
+        for step in 0..3 {
+            for i in 0..16 {
+                let xa0 = (16 * step + i) % 12;
+                let xa1 = (16 * step + i + 11) % 12;
+
+                a[xa0] = (a[xa0] ^ a[xa1].rotate_left(15).wrapping_mul(5) ^ c[(24 - i) % 16]).wrapping_mul(3)
+                    ^ b[(i + 13) % 16]
+                    ^ (b[(i + 9) % 16] & !b[(i + 6) % 16])
+                    ^ m[i];
+                b[i] = !(b[i].rotate_left(1) ^ a[xa0]);
+            }
+        }
+
+        for k in 0..36 {
+            a[11 - k % 12] = a[11 - k % 12].wrapping_add(c[(54 - k) % 16]);
+        }
+    }
+}
+
+fn decode_block(buf: &[u8; BLOCK_SIZE]) -> [u32; 16] {
+    let mut m = [0u32; 16];
+    for (word, chunk) in m.iter_mut().zip(buf.chunks(4)) {
+        *word = chunk[0] as u32
+            | (chunk[1] as u32) << 8
+            | (chunk[2] as u32) << 16
+            | (chunk[3] as u32) << 24;
+    }
+    m
 }
 
 pub fn shabal256(input: &[u8]) -> [u8; 32] {
-    let mut output: [u8; 32] = [0; 32];
-    unsafe {
-        let mut shabal_ctx: sph_shabal_context = Default::default();
-        let shabal_ctx_ptr: *mut libc::c_void = &mut shabal_ctx as *mut _ as *mut libc::c_void;
-        sph_shabal256_init(shabal_ctx_ptr);
-        sph_shabal256(shabal_ctx_ptr,
-                      &input[0] as *const _ as *const libc::c_void,
-                      input.len());
-        // println!("input:  {}", input.to_hex());
-        sph_shabal256_close(shabal_ctx_ptr,
-                            &mut output[0] as *mut _ as *mut libc::c_void);
-        // println!("output: {}", output.to_hex());
-    }
-    return output;
+    let mut hasher = Shabal256::new();
+    hasher.update(input);
+    hasher.finalize()
 }
 
-extern "C" {
-    pub fn sph_shabal256_init(cc: *mut libc::c_void);
-    pub fn sph_shabal256(cc: *mut libc::c_void,
-                         data: *const libc::c_void,
-                         input_size: libc::size_t);
-    pub fn sph_shabal256_close(cc: *mut libc::c_void, dst: *mut libc::c_void);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex::ToHex;
+
+    fn shabal256_hex(input: &[u8]) -> String {
+        let mut out = String::new();
+        shabal256(input).write_hex(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn sphlib_test_vectors() {
+        assert_eq!(shabal256_hex(b""), "aec750d11feee9f16271922fbaf5a9be142f62019ef8d720f858940070889014");
+        assert_eq!(shabal256_hex(&[0u8; 64]), "da8f08c02a67ba9a56bdd0798e48ae0714215e093b5b850649a37718993f54a2");
+        assert_eq!(shabal256_hex(b"abcdefghijklmnopqrstuvwxyz-0123456789-ABCDEFGHIJKLMNOPQRSTUVWXYZ-0123456789-abcdefghijklmnopqrstuvwxyz"),
+                   "b49f34bf51864c30533cc46cc2542bdec2f96fd06f5c539aff6ead5883f7327a");
+    }
+
+    #[test]
+    fn matches_c_implementation() {
+        // outputs recorded from the former sphlib FFI binding
+        assert_eq!(shabal256_hex(b"abc"), "07225fab83ca48fb480d22219410d5ca008359efbfd315829029afe2cb3f0404");
+
+        let input: Vec<u8> = (0..96u32).map(|i| i as u8).collect();
+        assert_eq!(shabal256_hex(&input), "2f8cb5c661e85623fb1be31518f2d220d68c8c1988df4ed38e68c9cbd5b71473");
+    }
+
+    #[test]
+    fn incremental_update_matches_one_shot() {
+        let input: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+
+        for split in &[0, 1, 31, 32, 63, 64, 65, 128, 299, 300] {
+            let mut hasher = Shabal256::new();
+            hasher.update(&input[..*split]);
+            let snapshot = hasher.clone();
+            hasher.update(&input[*split..]);
+            assert_eq!(hasher.finalize(), shabal256(&input), "split at {}", split);
+
+            let mut resumed = snapshot;
+            resumed.update(&input[*split..]);
+            assert_eq!(resumed.finalize(), shabal256(&input), "cloned at {}", split);
+        }
+    }
+
+    #[test]
+    fn reset_restores_initial_state() {
+        let mut hasher = Shabal256::new();
+        hasher.update(b"some data");
+        hasher.reset();
+        hasher.update(b"abc");
+        assert_eq!(hasher.finalize(), shabal256(b"abc"));
+    }
 }